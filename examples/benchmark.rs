@@ -1,4 +1,6 @@
 use serde::{Deserialize, Serialize};
+use sfsdb::database::simple::SimpleDB;
+use sfsdb::storage::LogStorage;
 use sfsdb::GenericDatabase;
 use std::fs;
 use std::time::SystemTime;
@@ -24,6 +26,7 @@ fn main() {
     let mut db_uncached = sfsdb::new("db_simple");
     let mut db_indexed = sfsdb::new_indexed::<MyIndex>("db_indexed", Some(20), 100);
     let mut db_cached = sfsdb::new_cached("db_cached", Some(20), 100);
+    let mut db_log = SimpleDB::from_storage(LogStorage::new("db_log"));
 
     let justin = User {
         name: "Justin Evans".to_string(),
@@ -47,6 +50,21 @@ fn main() {
         print!("(Simple) Loading justin (with key '400') 1000 times took: ");
     });
 
+    // Creating in a database that appends to a log instead of creating files
+    bench(&mut db_log, |db| {
+        for i in 0..1000 {
+            db.save(&i.to_string(), &justin).unwrap();
+        }
+        print!("\n(Log) Saving justin 1000 times took: ");
+    });
+    // Loading from a log database
+    bench(&mut db_log, |db| {
+        for _i in 0..1000 {
+            let _justin = db.load::<User>("400").unwrap();
+        }
+        print!("(Log) Loading justin (with key '400') 1000 times took: ");
+    });
+
     // Creating in an cached database
     bench(&mut db_cached, |db| {
         for i in 0..1000 {
@@ -106,7 +124,8 @@ fn main() {
         );
     });
 
-    for db in &["db_simple", "db_cached", "db_indexed"] {
+    drop((db_uncached, db_indexed, db_cached, db_log));
+    for db in &["db_simple", "db_log", "db_cached", "db_indexed"] {
         fs::remove_dir_all(db).ok();
     }
 }
@@ -117,5 +136,5 @@ where
 {
     let t = SystemTime::now();
     action(db);
-    print!("{:?}\n", t.elapsed().unwrap());
+    println!("{:?}", t.elapsed().unwrap());
 }
@@ -35,7 +35,7 @@ fn main() {
     db.save("some key", &u).unwrap();
     db.save("other key", &u).unwrap();
 
-    assert_eq!(db.exists("some key"), true);
+    assert!(db.exists("some key"));
     assert_eq!(u, db.load::<User>("some key").unwrap());
     assert_eq!(u, db.load::<User>("other key").unwrap());
 }
@@ -61,4 +61,15 @@ fn main() {
     let recent =
         db.search_with(|index| index.starting_date.elapsed().unwrap() > Duration::from_secs(300));
     println!("These have existed for more than 300 seconds: {:?}", recent);
+
+    // Queries that are made often can skip going through every index by keeping a tag index
+    db.create_tag_index("categories", |index: &MyIndex| index.categories.clone())
+        .unwrap();
+    let employees = db
+        .tags("categories")
+        .unwrap()
+        .all_of(&["employee"])
+        .none_of(&["programmers"])
+        .keys();
+    println!("All employees that aren't programmers: {:?}", employees);
 }
@@ -1,4 +1,13 @@
-use hashbrown::HashMap;
+pub mod pool;
+
+use crate::error::DBError;
+use crate::storage::{self, Storage};
+use hashbrown::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use std::any::{Any, TypeId};
+use std::sync::Arc;
+
+const CACHE_FILE: &str = "__CACHE__";
 
 pub struct Cache {
     pub count: HashMap<String, u64>,
@@ -7,6 +16,20 @@ pub struct Cache {
     pub limit: Option<usize>,
     pub full: bool,
     pub content: HashMap<String, Vec<u8>>,
+    // Already decoded values of cached keys, by the type they were decoded as
+    pub typed: HashMap<String, HashMap<TypeId, Arc<dyn Any + Send + Sync>>>,
+    // Keys that always stay cached regardless of usage
+    pub pinned: HashSet<String>,
+    // When attached to a pool, the limit is whatever share of it this cache gets
+    pub pool: Option<pool::PoolMember>,
+}
+
+// The part of the cache that survives a restart
+#[derive(Serialize, Deserialize, Default)]
+struct CacheState {
+    count: HashMap<String, u64>,
+    #[serde(default)]
+    pinned: HashSet<String>,
 }
 
 //pub struct CacheCount(pub HashMap<String, u64>);
@@ -17,7 +40,7 @@ impl Cache {
     pub fn increase_use(&mut self, identifier: &str) -> bool {
         match self.count.get_mut(identifier) {
             Some(c) => {
-                if *c != std::u64::MAX {
+                if *c != u64::MAX {
                     *c += 1
                 } else {
                     self.reset()
@@ -36,26 +59,144 @@ impl Cache {
         self.count.remove(key);
     }
 
+    pub fn get_typed<T: Any + Send + Sync>(&self, key: &str) -> Option<Arc<T>> {
+        let value = self.typed.get(key)?.get(&TypeId::of::<T>())?;
+        value.clone().downcast::<T>().ok()
+    }
+
+    pub fn put_typed<T: Any + Send + Sync>(&mut self, key: &str, value: Arc<T>) {
+        self.typed
+            .entry(key.to_owned())
+            .or_default()
+            .insert(TypeId::of::<T>(), value);
+    }
+
+    // Remove a key from both the byte and decoded cache
+    pub fn evict(&mut self, key: &str) {
+        self.content.remove(key);
+        self.typed.remove(key);
+    }
+
     pub fn should_resync(&self) -> bool {
         self.timer > self.when_to_sync
     }
 
+    // Whether the amount of tracked keys has reached the limit
+    pub fn at_limit(&self) -> bool {
+        match self.limit {
+            Some(limit) => self.count.len() >= limit,
+            None => false,
+        }
+    }
+
+    // Keys sorted by usage, most used first
+    pub fn ranked(&self) -> Vec<&String> {
+        let mut pairs: Vec<(&String, &u64)> = self.count.iter().collect();
+        pairs.sort_by(|(_, a_v), (_, b_v)| b_v.cmp(a_v));
+        pairs.into_iter().map(|(k, _)| k).collect()
+    }
+
+    // Ask the pool, if any, for how many values this cache may currently hold
+    pub fn update_limit(&mut self) {
+        let member = match &self.pool {
+            Some(member) => member,
+            None => return,
+        };
+        let mut demand: Vec<u64> = self
+            .count
+            .iter()
+            .map(|(k, c)| {
+                if self.pinned.contains(k) {
+                    u64::MAX
+                } else {
+                    *c
+                }
+            })
+            .collect();
+        demand.sort_by(|a, b| b.cmp(a));
+        self.limit = Some(member.quota(demand));
+    }
+
+    // Take over a new share of the pool, if it was rebalanced since this cache last looked.
+    // Returns whether the share shrunk.
+    pub fn rebalance(&mut self) -> bool {
+        let quota = match self.pool.as_ref().and_then(|member| member.rebalanced()) {
+            Some(quota) => quota,
+            None => return false,
+        };
+        let shrunk = self.limit.map_or(true, |limit| quota < limit);
+        self.limit = Some(quota);
+        shrunk
+    }
+
+    // The keys that should be cached after a resync. Pinned keys always are, and take up room
+    // that would otherwise go to the most used keys.
+    pub fn wanted(&self) -> Vec<&String> {
+        let room = self
+            .limit
+            .map(|limit| limit.saturating_sub(self.pinned.len()))
+            .unwrap_or(usize::MAX);
+        let mut wanted: Vec<&String> = self.pinned.iter().collect();
+        wanted.extend(
+            self.ranked()
+                .into_iter()
+                .filter(|k| !self.pinned.contains(*k))
+                .take(room),
+        );
+        wanted
+    }
+
     // This prevents integer overflows by pushing back all values, maintaining the percentual
     // difference between the counts.
     pub fn reset(&mut self) {
         for count in self.count.values_mut() {
-            *count = *count / 5;
+            *count /= 5;
+        }
+    }
+
+    pub fn disk_save<S: Storage>(&self, storage: &mut S) -> Result<(), DBError> {
+        storage::save(
+            storage,
+            CACHE_FILE,
+            &CacheState {
+                count: self.count.clone(),
+                pinned: self.pinned.clone(),
+            },
+        )
+    }
+
+    // Restore the usage counts of a previous run, dropping keys that have since been removed.
+    pub fn disk_load<S: Storage>(&mut self, storage: &S) {
+        if !storage.contains(CACHE_FILE) {
+            return;
+        }
+        let state = storage::load::<CacheState, S>(storage, CACHE_FILE).unwrap_or_else(|e| {
+            eprintln!("sfsdb: Discarding unreadable cache state: {}", e);
+            CacheState::default()
+        });
+        for (key, count) in state.count {
+            if storage.contains(&key) {
+                self.count.insert(key, count);
+            }
+        }
+        for key in state.pinned {
+            if self.count.contains_key(&key) {
+                self.pinned.insert(key);
+            }
         }
     }
 
     pub fn new(limit: Option<usize>, resync_every: u16) -> Self {
         Cache {
             count: HashMap::new(),
-            limit: limit,
+            limit,
             when_to_sync: resync_every,
             full: false,
             timer: 0,
             content: HashMap::new(),
+            typed: HashMap::new(),
+            pinned: HashSet::new(),
+            pool: None,
         }
     }
 }
@@ -0,0 +1,136 @@
+use hashbrown::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A cache limit shared between several cached or indexed databases, so that the values cached
+/// across all of them never exceed one global amount.
+///
+/// Each database attached to the pool gets to cache as many values as it has among the globally
+/// most used ones, so busy databases get a bigger share than idle ones. Shares are recomputed
+/// whenever an attached database resyncs, and the others shrink to their new share on their next
+/// save or load.
+///
+/// ```no_run
+/// let pool = sfsdb::CachePool::new(1000);
+/// let users = sfsdb::new_cached("users", None, 100).with_pool(&pool);
+/// let posts = sfsdb::new_cached("posts", None, 100).with_pool(&pool);
+/// ```
+#[derive(Clone)]
+pub struct CachePool {
+    state: Arc<Mutex<PoolState>>,
+    // Bumped whenever shares change, so members can tell without locking
+    generation: Arc<AtomicU64>,
+}
+
+struct PoolState {
+    limit: usize,
+    next_id: u64,
+    // Usage counts of the most used keys of every member, most used first
+    demand: HashMap<u64, Vec<u64>>,
+    // The share of every member as of the last recompute
+    quotas: HashMap<u64, usize>,
+}
+
+impl CachePool {
+    /// Create a pool allowing `limit` values to be cached in total.
+    pub fn new(limit: usize) -> Self {
+        CachePool {
+            state: Arc::new(Mutex::new(PoolState {
+                limit,
+                next_id: 0,
+                demand: HashMap::new(),
+                quotas: HashMap::new(),
+            })),
+            generation: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// The maximum amount of values cached across all attached databases.
+    pub fn limit(&self) -> usize {
+        self.lock().limit
+    }
+
+    /// The amount of databases currently attached.
+    pub fn members(&self) -> usize {
+        self.lock().demand.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        // A panic elsewhere doesn't make the usage counts any less valid
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn join(&self) -> PoolMember {
+        let mut state = self.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.demand.insert(id, Vec::new());
+        PoolMember {
+            pool: self.clone(),
+            id,
+            seen: AtomicU64::new(self.generation.load(Ordering::Acquire)),
+        }
+    }
+}
+
+// A database's membership of a pool, which ends when dropped
+pub struct PoolMember {
+    pool: CachePool,
+    id: u64,
+    // The generation of shares this member last took over
+    seen: AtomicU64,
+}
+
+impl PoolMember {
+    pub fn pool(&self) -> &CachePool {
+        &self.pool
+    }
+
+    // Report this member's usage counts (most used first) and get back how many values it may
+    // cache, which is how many of its counts are among the pool's top `limit`. The shares of
+    // every other member are recomputed along with it.
+    pub fn quota(&self, mut demand: Vec<u64>) -> usize {
+        let mut state = self.pool.lock();
+        let limit = state.limit;
+        demand.truncate(limit);
+        state.demand.insert(self.id, demand);
+
+        let mut all: Vec<(u64, bool, u64)> = state
+            .demand
+            .iter()
+            .flat_map(|(id, counts)| counts.iter().map(move |c| (*c, *id == self.id, *id)))
+            .collect();
+        // On equal counts the other members go first, so nobody can take over a full pool
+        // without actually being used more
+        all.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+        let mut quotas: HashMap<u64, usize> = state.demand.keys().map(|id| (*id, 0)).collect();
+        for (_, _, id) in all.into_iter().take(limit) {
+            *quotas.entry(id).or_default() += 1;
+        }
+        let own = quotas[&self.id];
+        if quotas != state.quotas {
+            state.quotas = quotas;
+            let generation = self.pool.generation.fetch_add(1, Ordering::AcqRel) + 1;
+            self.seen.store(generation, Ordering::Release);
+        }
+        own
+    }
+
+    // This member's new share if shares changed since it last looked, which only takes a lock
+    // when they did
+    pub fn rebalanced(&self) -> Option<usize> {
+        let generation = self.pool.generation.load(Ordering::Acquire);
+        if self.seen.swap(generation, Ordering::AcqRel) == generation {
+            return None;
+        }
+        self.pool.lock().quotas.get(&self.id).copied()
+    }
+}
+
+impl Drop for PoolMember {
+    fn drop(&mut self) {
+        let mut state = self.pool.lock();
+        state.demand.remove(&self.id);
+        state.quotas.remove(&self.id);
+    }
+}
@@ -1,72 +1,359 @@
 use crate::cache::Cache;
+use crate::database::collection::{self, Collection};
 use crate::error::DBError;
-use crate::filesystem::{fs_delete, fs_load, fs_save};
-use crate::GenericDatabase;
-use std::fs::read;
+use crate::presence::Presence;
+use crate::sequence;
+use crate::storage::{self, FileStorage, Storage};
+use crate::CachePool;
+use crate::{GenericDatabase, Key};
 
 use hashbrown::HashMap;
 use rmp_serde::{decode, encode};
 use serde::{Deserialize, Serialize};
 
-use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 
-pub struct CachedDB {
-    location: String,
+pub struct CachedDB<S: Storage = FileStorage> {
+    storage: Shared<S>,
     cache: Cache,
+    write_back: Option<Arc<Mutex<WriteBack>>>,
+    // Stops the flushing thread when dropped
+    flusher: Option<Sender<()>>,
+    presence: Presence,
+}
+
+// Saves that so far only went to the cache, and when to flush them
+struct WriteBack {
+    max_dirty_bytes: usize,
+    interval: Duration,
+    dirty: HashMap<String, Vec<u8>>,
+    dirty_bytes: usize,
+    last_flush: Instant,
+}
+
+impl WriteBack {
+    fn is_due(&self) -> bool {
+        self.dirty_bytes > self.max_dirty_bytes || self.last_flush.elapsed() >= self.interval
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Storage shared with the thread that flushes in write-back mode. Every call holds the lock for
+// just as long as it runs.
+struct Shared<S> {
+    storage: Arc<Mutex<S>>,
+    location: String,
+}
+
+impl<S: Storage> Shared<S> {
+    fn new(storage: S) -> Self {
+        Shared {
+            location: storage.location().to_owned(),
+            storage: Arc::new(Mutex::new(storage)),
+        }
+    }
+
+    // A handle that doesn't keep the storage around once the database is dropped
+    fn downgrade(&self) -> (Weak<Mutex<S>>, String) {
+        (Arc::downgrade(&self.storage), self.location.clone())
+    }
+
+    fn upgrade((storage, location): &(Weak<Mutex<S>>, String)) -> Option<Self> {
+        Some(Shared {
+            storage: storage.upgrade()?,
+            location: location.clone(),
+        })
+    }
 }
 
-impl GenericDatabase for CachedDB {
+impl<S: Storage> Storage for Shared<S> {
     fn location(&self) -> &str {
         &self.location
     }
-    fn exists(&self, key: &str) -> bool {
-        if self.cache.limit.is_some() {
-            let mut p = PathBuf::new();
-            p.push(&self.location);
-            p.push(key);
-            p.exists()
-        } else {
-            self.cache.content.contains_key(key)
+    fn is_removed(&self) -> bool {
+        lock(&self.storage).is_removed()
+    }
+    fn get(&self, key: &str) -> Result<Vec<u8>, DBError> {
+        lock(&self.storage).get(key)
+    }
+    fn get_many(&self, keys: &[&str]) -> Vec<Result<Vec<u8>, DBError>> {
+        lock(&self.storage).get_many(keys)
+    }
+    fn put(&mut self, key: &str, data: &[u8]) -> Result<(), DBError> {
+        lock(&self.storage).put(key, data)
+    }
+    fn put_durable(&mut self, key: &str, data: &[u8]) -> Result<(), DBError> {
+        lock(&self.storage).put_durable(key, data)
+    }
+    fn append(&mut self, key: &str, data: &[u8]) -> Result<(), DBError> {
+        lock(&self.storage).append(key, data)
+    }
+    fn delete(&mut self, key: &str) -> Result<(), DBError> {
+        lock(&self.storage).delete(key)
+    }
+    fn contains(&self, key: &str) -> bool {
+        lock(&self.storage).contains(key)
+    }
+    fn list(&self) -> Vec<String> {
+        lock(&self.storage).list()
+    }
+    fn list_children(&self, prefix: &str) -> Vec<String> {
+        lock(&self.storage).list_children(prefix)
+    }
+    fn delete_tree(&mut self, prefix: &str) -> Result<(), DBError> {
+        lock(&self.storage).delete_tree(prefix)
+    }
+    fn namespace(&self, name: &str) -> Result<Self, DBError> {
+        Ok(Shared::new(lock(&self.storage).namespace(name)?))
+    }
+}
+
+// Write every dirty value to storage. What couldn't be written stays dirty, so it's retried on
+// the next flush. The write-back lock is always taken before the storage lock.
+fn flush_dirty<S: Storage>(
+    write_back: &Mutex<WriteBack>,
+    storage: &mut Shared<S>,
+) -> Result<(), DBError> {
+    let mut wb = lock(write_back);
+    let mut result = Ok(());
+    let dirty: Vec<(String, Vec<u8>)> = wb.dirty.drain().collect();
+    for (key, value) in dirty {
+        if let Err(e) = storage.put(&key, &value) {
+            wb.dirty.insert(key, value);
+            result = Err(e);
+        }
+    }
+    wb.dirty_bytes = wb.dirty.values().map(Vec::len).sum();
+    wb.last_flush = Instant::now();
+    result
+}
+
+impl<S: Storage> GenericDatabase for CachedDB<S> {
+    fn location(&self) -> &str {
+        self.storage.location()
+    }
+    fn exists(&self, key: &(impl Key + ?Sized)) -> bool {
+        let key = match key.encode_key() {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        if self.cache.content.contains_key(&key) || self.unflushed(&key).is_some() {
+            return true;
+        }
+        if self.presence.absent(&key) {
+            return false;
         }
+        self.storage.contains(&key)
     }
-    fn save<T>(&mut self, key: &str, value: &T) -> Result<(), DBError>
+    fn save<T>(&mut self, key: &(impl Key + ?Sized), value: &T) -> Result<(), DBError>
     where
         for<'de> T: Deserialize<'de> + Serialize + Clone,
     {
-        let mut path = PathBuf::new();
-        path.push(&self.location());
-        path.push(key);
-        fs_save(&path, &value)?;
+        let k = key.encode_key()?;
+        let key = k.as_str();
+        let encoded = match encode::to_vec(value) {
+            Ok(v) => v,
+            Err(_) => return Err(DBError::save(&format!("Unable to serialize {}", k))),
+        };
+        self.follow_pool();
 
-        let k = key.to_owned();
+        match &self.write_back {
+            // Only kept until the next flush, which is where loads find it until then
+            Some(wb) => {
+                let mut wb = lock(wb);
+                wb.dirty_bytes += encoded.len();
+                wb.dirty.insert(k.clone(), encoded.clone());
+            }
+            None => self.storage.put(key, &encoded)?,
+        }
 
         // Keep track of usage for syncing
         self.cache.add_tracker(k.clone());
+        self.cache.typed.remove(key);
+        self.presence.saved(key);
+        self.maintain_presence();
 
         // Put everything on the cache untill it's full. And then start to resync from there
-        if !self.cache.full {
-            match self.cache.limit {
-                Some(limit) => {
-                    if self.cache.count.len() == limit {
-                        self.cache.full = true
-                    }
-                }
-                None => (),
+        if !self.cache.full || self.cache.content.contains_key(key) {
+            if self.cache.at_limit() {
+                self.cache.full = true
             }
-            self.cache.content.insert(
-                k.clone(),
-                match encode::to_vec(value) {
-                    Ok(v) => v,
-                    Err(_) => return Err(DBError::save(&format!("Unable to serialize {}", k))),
-                },
-            );
+            self.cache.content.insert(k, encoded);
         }
-        Ok(())
+        self.flush_if_due()
     }
-    fn load<T>(&mut self, key: &str) -> Result<T, DBError>
+    fn load<T>(&mut self, key: &(impl Key + ?Sized)) -> Result<T, DBError>
     where
         for<'de> T: Deserialize<'de> + Serialize + Clone,
     {
+        let key = key.encode_key()?;
+        self.track_load(&key);
+        self.flush_on_load();
+        self.fetch(&key)
+    }
+    fn delete(&mut self, key: &(impl Key + ?Sized)) {
+        let key = match key.encode_key() {
+            Ok(key) => key,
+            Err(e) => return eprintln!("{}", e),
+        };
+        let key = key.as_str();
+        self.cache.evict(key);
+        self.cache.del_tracker(key);
+        self.cache.pinned.remove(key);
+        let unflushed = self.write_back.as_ref().is_some_and(|wb| {
+            let mut wb = lock(wb);
+            match wb.dirty.remove(key) {
+                Some(value) => {
+                    wb.dirty_bytes = wb.dirty_bytes.saturating_sub(value.len());
+                    true
+                }
+                None => false,
+            }
+        });
+        self.presence.deleted(key);
+        self.maintain_presence();
+
+        if unflushed && !self.storage.contains(key) {
+            return;
+        }
+        self.storage
+            .delete(key)
+            .map_err(|e| eprintln!("{}", e))
+            .ok();
+    }
+    fn keys(&self) -> Vec<String> {
+        // Every key is tracked, including those that haven't been flushed yet
+        let mut keys: Vec<String> = self.cache.count.keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+}
+
+impl CachedDB {
+    pub fn new(location: &str, cache_limit: Option<usize>, resync_every: u16) -> Self {
+        CachedDB::from_storage(FileStorage::new(location), cache_limit, resync_every)
+    }
+}
+
+impl<S: Storage> CachedDB<S> {
+    /// Load a value of type T, but keep the decoded value around for as long as the key stays
+    /// cached. Subsequent loads of the same key as the same type skip deserialization entirely and
+    /// return a shared reference to the same value.
+    pub fn load_shared<T>(&mut self, key: &(impl Key + ?Sized)) -> Result<Arc<T>, DBError>
+    where
+        for<'de> T: Deserialize<'de> + Send + Sync + 'static,
+    {
+        let key = key.encode_key()?;
+        self.track_load(&key);
+        self.flush_on_load();
+        if let Some(v) = self.cache.get_typed::<T>(&key) {
+            return Ok(v);
+        }
+        let v = Arc::new(self.fetch::<T>(&key)?);
+        if self.cache.content.contains_key(&key) {
+            self.cache.put_typed(&key, v.clone());
+        }
+        Ok(v)
+    }
+
+    /// Load the values of several keys at once, in the same order as keys. Keys that aren't
+    /// cached are read from storage together, which is faster than one by one for backends that
+    /// batch reads.
+    pub fn load_many<T, K>(&mut self, keys: &[K]) -> Result<Vec<T>, DBError>
+    where
+        for<'de> T: Deserialize<'de>,
+        K: Key,
+    {
+        let keys = keys
+            .iter()
+            .map(Key::encode_key)
+            .collect::<Result<Vec<String>, DBError>>()?;
+        for key in &keys {
+            self.track_load(key);
+        }
+        self.flush_on_load();
+
+        let missing: Vec<&str> = keys
+            .iter()
+            .map(String::as_str)
+            .filter(|key| {
+                !self.cache.content.contains_key(*key)
+                    && self.unflushed(key).is_none()
+                    && !self.presence.absent(key)
+            })
+            .collect();
+        let mut read: HashMap<&str, Result<Vec<u8>, DBError>> = missing
+            .iter()
+            .copied()
+            .zip(self.storage.get_many(&missing))
+            .collect();
+
+        let mut values = Vec::with_capacity(keys.len());
+        for key in &keys {
+            let bytes = match read.remove(key.as_str()) {
+                Some(bytes) => bytes,
+                // Cached, known to be absent or read already
+                None => {
+                    values.push(self.fetch(key)?);
+                    continue;
+                }
+            };
+            let bytes = match bytes {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    if !self.storage.contains(key) {
+                        self.presence.missed(key);
+                    }
+                    return Err(e);
+                }
+            };
+            values.push(match decode::from_slice(&bytes) {
+                Ok(v) => v,
+                Err(e) => return Err(DBError::load(&format!("Unable to decode {} ({})", key, e))),
+            });
+            // Same as fetch, keys from an earlier run are cached on first use
+            if !self.cache.full && self.cache.count.contains_key(key) {
+                self.cache.content.insert(key.clone(), bytes);
+            }
+        }
+        Ok(values)
+    }
+
+    // The encoded value of key, without counting it as a load
+    pub(crate) fn load_bytes(&self, key: &str) -> Result<Vec<u8>, DBError> {
+        if let Some(bytes) = self.cache.content.get(key) {
+            return Ok(bytes.clone());
+        }
+        match self.unflushed(key) {
+            Some(bytes) => Ok(bytes),
+            None => self.storage.get(key),
+        }
+    }
+
+    // The latest save of key, if it wasn't flushed yet
+    fn unflushed(&self, key: &str) -> Option<Vec<u8>> {
+        lock(self.write_back.as_ref()?).dirty.get(key).cloned()
+    }
+
+    // Shrink down to a smaller share of the pool as soon as another member took some of it
+    fn follow_pool(&mut self) {
+        if self.cache.rebalance() {
+            self.evict_unwanted();
+            if self.cache.at_limit() {
+                self.cache.full = true;
+            }
+        }
+    }
+
+    // Usage tracking that needs to happen on every load
+    fn track_load(&mut self, key: &str) {
+        self.follow_pool();
         // Perform resync once ever X amount of loads
         if self.cache.should_resync() {
             self.cache.timer = 0;
@@ -77,15 +364,40 @@ impl GenericDatabase for CachedDB {
         }
         self.cache.timer += 1;
         self.cache.increase_use(key);
+    }
+
+    fn fetch<T>(&mut self, key: &str) -> Result<T, DBError>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        if let Some(bytes) = self.unflushed(key) {
+            if !self.cache.content.contains_key(key) {
+                return match decode::from_slice(&bytes) {
+                    Ok(v) => Ok(v),
+                    Err(e) => Err(DBError::load(&format!("Unable to decode {} ({})", key, e))),
+                };
+            }
+        }
         match self.cache.content.get(key) {
             None => {
-                let mut path = PathBuf::new();
-                path.push(&self.location());
-                path.push(key);
-                match fs_load::<T>(&path) {
-                    Ok(v) => Ok(v),
-                    Err(e) => Err(e),
+                if self.presence.absent(key) {
+                    return Err(DBError::load(&format!("{} does not exist", key)));
                 }
+                if self.cache.full || !self.cache.count.contains_key(key) {
+                    let v = storage::load::<T, Shared<S>>(&self.storage, key);
+                    if v.is_err() && !self.storage.contains(key) {
+                        self.presence.missed(key);
+                    }
+                    return v;
+                }
+                // Keys from an earlier run are cached on first use instead of on save
+                let bytes = self.storage.get(key)?;
+                let v = match decode::from_slice(&bytes) {
+                    Ok(v) => v,
+                    Err(e) => return Err(DBError::load(&format!("{}", e))),
+                };
+                self.cache.content.insert(key.to_owned(), bytes);
+                Ok(v)
             }
             Some(v) => Ok(match decode::from_slice(v) {
                 Ok(v) => v,
@@ -93,74 +405,464 @@ impl GenericDatabase for CachedDB {
             }),
         }
     }
-    fn delete(&mut self, key: &str) {
-        self.cache.content.remove(key);
-        self.cache.del_tracker(key);
 
-        let mut path = PathBuf::new();
-        path.push(&self.location());
-        path.push(key);
-        fs_delete(&path);
+    /// The next number of a sequence, starting at 1. See
+    /// [`SimpleDB::next_id`](../simple/struct.SimpleDB.html#method.next_id).
+    pub fn next_id(&mut self, sequence: &str) -> Result<u64, DBError> {
+        // Straight to disk even in write-back mode, a sequence must never go backwards
+        sequence::next_id(&mut self.storage, sequence)
+    }
+
+    /// Save a value under the next number of the sequence `id`, which is returned.
+    pub fn insert<T>(&mut self, value: &T) -> Result<u64, DBError>
+    where
+        for<'de> T: Deserialize<'de> + Serialize + Clone,
+    {
+        let id = self.next_id(sequence::INSERT_SEQUENCE)?;
+        self.save(&id, value)?;
+        Ok(id)
+    }
+
+    /// Save a value under a new [`ulid`](../../fn.ulid.html), which is returned.
+    pub fn insert_ulid<T>(&mut self, value: &T) -> Result<String, DBError>
+    where
+        for<'de> T: Deserialize<'de> + Serialize + Clone,
+    {
+        let id = sequence::ulid();
+        self.save(&id, value)?;
+        Ok(id)
+    }
+
+    /// A typed collection stored in its own subdirectory, with the same cache limit (or pool)
+    /// and resync schedule as this database. See
+    /// [`Collection`](../collection/struct.Collection.html).
+    pub fn collection<T>(&self, name: &str) -> Result<Collection<String, T, CachedDB<S>>, DBError>
+    where
+        for<'de> T: Deserialize<'de> + Serialize + Clone,
+    {
+        let (cache_limit, resync_every) = self.cache_settings();
+        let mut db = CachedDB::from_storage(self.child_storage(name)?, cache_limit, resync_every);
+        if let Some(pool) = self.pool() {
+            db = db.with_pool(&pool);
+        }
+        Ok(Collection::new(db))
+    }
+
+    /// A typed collection stored in its own subdirectory, with its own cache settings.
+    pub fn collection_with<T>(
+        &self,
+        name: &str,
+        cache_limit: Option<usize>,
+        resync_every: u16,
+    ) -> Result<Collection<String, T, CachedDB<S>>, DBError>
+    where
+        for<'de> T: Deserialize<'de> + Serialize + Clone,
+    {
+        Ok(Collection::new(CachedDB::from_storage(
+            self.child_storage(name)?,
+            cache_limit,
+            resync_every,
+        )))
+    }
+
+    pub(crate) fn child_storage(&self, name: &str) -> Result<S, DBError> {
+        collection::check_name(name)?;
+        lock(&self.storage.storage).namespace(name)
+    }
+
+    pub(crate) fn cache_settings(&self) -> (Option<usize>, u16) {
+        (self.cache.limit, self.cache.when_to_sync)
+    }
+
+    pub(crate) fn pool(&self) -> Option<CachePool> {
+        self.cache.pool.as_ref().map(|member| member.pool().clone())
+    }
+
+    /// The names directly below prefix when keys are read as `/` separated paths, including
+    /// saves that haven't been flushed yet. See
+    /// [`Storage::list_children`](../../storage/trait.Storage.html#method.list_children).
+    pub fn list_children(&self, prefix: &str) -> Vec<String> {
+        let mut children = self.storage.list_children(prefix);
+        if let Some(wb) = &self.write_back {
+            let dirty: Vec<String> = lock(wb).dirty.keys().cloned().collect();
+            children.extend(storage::children(&dirty, prefix));
+            children.sort();
+            children.dedup();
+        }
+        children
+    }
+
+    /// Remove prefix and every key below it, from the cache as well as from disk.
+    pub fn delete_tree(&mut self, prefix: &str) -> Result<(), DBError> {
+        let root = storage::tree_root(prefix)?;
+        let keys: Vec<String> = self
+            .cache
+            .count
+            .keys()
+            .filter(|k| storage::in_tree(k, root))
+            .cloned()
+            .collect();
+        for key in &keys {
+            self.cache.evict(key);
+            self.cache.del_tracker(key);
+            self.cache.pinned.remove(key);
+            self.presence.deleted(key);
+        }
+        if let Some(wb) = &self.write_back {
+            let mut wb = lock(wb);
+            wb.dirty.retain(|key, _| !storage::in_tree(key, root));
+            wb.dirty_bytes = wb.dirty.values().map(Vec::len).sum();
+        }
+        self.maintain_presence();
+        self.storage.delete_tree(root)
     }
-}
 
-impl CachedDB {
     /// Manually perform a resync of the cache. This will cache the top N most used keys.
     /// This is already run automatically on a schedule.
     pub fn resync(&mut self) {
-        let mut pairs = Vec::with_capacity(self.cache.count.len());
-        let mut i: usize = 0;
-        for (key, value) in &self.cache.count {
-            pairs.push((key, value));
-            i += 1;
+        self.cache.update_limit();
+        self.fill(usize::MAX);
+        self.evict_unwanted();
+
+        // Resyncs happen on a schedule, which makes them a good time to also persist the usage
+        self.persist().map_err(|e| eprintln!("{}", e)).ok();
+    }
+
+    // Drop every cached value that isn't among the wanted keys
+    fn evict_unwanted(&mut self) {
+        let should_exist: HashMap<String, ()> = self
+            .cache
+            .wanted()
+            .into_iter()
+            .map(|k| (k.clone(), ()))
+            .collect();
+        self.cache
+            .content
+            .retain(|key, _| should_exist.contains_key(key));
+        self.cache
+            .typed
+            .retain(|key, _| should_exist.contains_key(key));
+    }
+
+    /// Write the usage statistics of the cache to the database directory, so the next time it's
+    /// opened it knows which keys are the most used ones. This is already done on every resync
+    /// and when the database is dropped.
+    pub fn persist(&mut self) -> Result<(), DBError> {
+        self.cache.disk_save(&mut self.storage)
+    }
+
+    /// Eagerly load the top N most used keys (from previous runs) into the cache, instead of
+    /// waiting for the first resync.
+    pub fn with_warm_up(mut self, top: usize) -> Self {
+        self.fill(self.cache.pinned.len() + top);
+        self
+    }
+
+    /// Share the cache limit with other databases through a pool, instead of using the limit
+    /// this database was opened with. See [`CachePool`](../../struct.CachePool.html).
+    pub fn with_pool(mut self, pool: &CachePool) -> Self {
+        self.cache.pool = Some(pool.join());
+        self.cache.update_limit();
+        self.cache.full = self.cache.at_limit();
+        self
+    }
+
+    /// Write all saves that so far only exist in the cache to disk. Does nothing unless in
+    /// write-back mode.
+    pub fn flush(&mut self) -> Result<(), DBError> {
+        match &self.write_back {
+            Some(wb) => flush_dirty(wb, &mut self.storage),
+            None => Ok(()),
         }
+    }
 
-        // Sort by one of tuple
-        pairs.sort_by(|(_, a_v), (_, b_v)| b_v.cmp(a_v));
+    // A flush that fails has nothing to do with the value being loaded, so it's only reported.
+    // What couldn't be written stays dirty and is retried on the next flush.
+    fn flush_on_load(&mut self) {
+        self.flush_if_due().map_err(|e| eprintln!("{}", e)).ok();
+    }
 
-        let mut should_exist: HashMap<&str, ()> = HashMap::new();
-        // Iter of those that are supposed to be in new cache
-        for (c, (k, _)) in pairs.iter().enumerate() {
-            // Stop conditionals
-            if c > i {
-                break;
-            } else if self.cache.limit.is_some() {
-                if c >= self.cache.limit.unwrap() {
-                    break;
-                };
+    fn flush_if_due(&mut self) -> Result<(), DBError> {
+        let due = match &self.write_back {
+            Some(wb) => lock(wb).is_due(),
+            None => false,
+        };
+        if due {
+            self.flush()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Keep a bloom filter of all keys in memory, so that `exists` and `load` on keys that were
+    /// never saved can be answered without touching the file system. See
+    /// [`SimpleDB::with_bloom_filter`](../simple/struct.SimpleDB.html#method.with_bloom_filter).
+    pub fn with_bloom_filter(mut self, expected_keys: usize) -> Self {
+        self.presence
+            .enable_bloom(expected_keys, self.cache.count.keys().map(String::as_str));
+        self
+    }
+
+    /// Remember up to `capacity` keys that were looked up but don't exist, so that looking them
+    /// up again doesn't touch the file system.
+    pub fn with_negative_cache(mut self, capacity: usize) -> Self {
+        self.presence.enable_negative_cache(capacity);
+        self
+    }
+
+    // Every existing key is tracked, so there's no need to go to the file system for rebuilds
+    fn maintain_presence(&mut self) {
+        if self.presence.should_rebuild() {
+            self.presence
+                .rebuild(self.cache.count.keys().map(String::as_str));
+        }
+    }
+
+    /// Keep a key in the cache at all times, regardless of how often it's used. Pinned keys are
+    /// loaded immediately, count towards the cache limit and stay pinned across restarts.
+    pub fn pin(&mut self, key: &(impl Key + ?Sized)) -> Result<(), DBError> {
+        let key = key.encode_key()?;
+        let key = key.as_str();
+        if !self.cache.count.contains_key(key) {
+            return Err(DBError::load(&format!(
+                "Cannot pin {}, it doesn't exist",
+                key
+            )));
+        }
+        if !self.cache.content.contains_key(key) {
+            let bytes = self.load_bytes(key)?;
+            self.cache.content.insert(key.to_owned(), bytes);
+        }
+        self.cache.pinned.insert(key.to_owned());
+        self.persist()
+    }
+
+    /// Let a pinned key be cached based on usage again. It's evicted on the next resync unless
+    /// it's one of the most used keys.
+    pub fn unpin(&mut self, key: &(impl Key + ?Sized)) -> Result<(), DBError> {
+        if self.cache.pinned.remove(&key.encode_key()?) {
+            self.persist()?;
+        }
+        Ok(())
+    }
+
+    /// All currently pinned keys.
+    pub fn pinned(&self) -> Vec<String> {
+        self.cache.pinned.iter().cloned().collect()
+    }
+
+    // Read the wanted keys from disk into the cache (pinned first, then the most used), if they
+    // aren't already cached
+    fn fill(&mut self, top: usize) {
+        let missing: Vec<String> = self
+            .cache
+            .wanted()
+            .into_iter()
+            .take(top)
+            .filter(|k| !self.cache.content.contains_key(*k))
+            .cloned()
+            .collect();
+        for k in missing {
+            let value_from_fs = self
+                .load_bytes(&k)
+                .map_err(|e| eprintln!("sfsdb: File and Cache mismatch ({}): {}", k, e))
+                .unwrap_or_default();
+            self.cache.content.insert(k, value_from_fs);
+        }
+    }
+
+    /// Use any storage backend instead of a directory.
+    pub fn from_storage(storage: S, cache_limit: Option<usize>, resync_every: u16) -> Self {
+        let mut cache = Cache::new(cache_limit, resync_every);
+        cache.disk_load(&storage);
+
+        // Keys saved by an earlier run that weren't tracked yet are just as eligible for the cache
+        for key in storage.list() {
+            if !cache.count.contains_key(&key) {
+                cache.add_tracker(key);
             }
+        }
+        cache.full = cache.at_limit();
 
-            should_exist.insert(k.clone(), ());
-            let value_from_fs = if self.cache.content.contains_key(*k) {
-                continue;
-            } else {
-                let mut path = PathBuf::new();
-                path.push(&self.location);
-                path.push(*k);
-                read(path)
-                    .map_err(|e| eprintln!("sfsdb: File and Cache mismatch ({}): {}", *k, e))
-                    .unwrap_or_default()
-            };
-            self.cache
-                .content
-                .insert(k.clone().to_owned(), value_from_fs);
+        let mut db = CachedDB {
+            storage: Shared::new(storage),
+            cache,
+            write_back: None,
+            flusher: None,
+            presence: Presence::default(),
+        };
+        // Pinned keys are always in memory, right from the start
+        db.fill(db.cache.pinned.len());
+        db
+    }
+}
+
+impl<S: Storage + Send + 'static> CachedDB<S> {
+    /// Switch to write-back mode, where saves only go to the cache and are written to disk later
+    /// in bulk. Unflushed saves are written when they add up to more than `max_dirty_bytes`
+    /// (checked on every save and load), by a background thread every `interval`, on
+    /// [`flush`](#method.flush) and when the database is dropped. Loads always see the latest
+    /// save.
+    ///
+    /// A flush that fails on a save returns the error, one that fails on a load or in the
+    /// background is printed to stderr and retried on the next flush. Saves that haven't been
+    /// flushed yet are lost if the process crashes.
+    pub fn with_write_back(mut self, max_dirty_bytes: usize, interval: Duration) -> Self {
+        let write_back = Arc::new(Mutex::new(WriteBack {
+            max_dirty_bytes,
+            interval,
+            dirty: HashMap::new(),
+            dirty_bytes: 0,
+            last_flush: Instant::now(),
+        }));
+        // Without an interval every save is flushed right away, so there's nothing to wait for
+        if interval > Duration::from_secs(0) {
+            let (stop, stopped) = mpsc::channel::<()>();
+            let wb = Arc::downgrade(&write_back);
+            let storage = self.storage.downgrade();
+            thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    let (wb, mut storage) = match (wb.upgrade(), Shared::upgrade(&storage)) {
+                        (Some(wb), Some(storage)) => (wb, storage),
+                        _ => return,
+                    };
+                    // Saves and loads may have flushed in the meantime
+                    if lock(&wb).last_flush.elapsed() < interval {
+                        continue;
+                    }
+                    flush_dirty(&wb, &mut storage)
+                        .map_err(|e| eprintln!("{}", e))
+                        .ok();
+                }
+            });
+            self.flusher = Some(stop);
         }
+        self.write_back = Some(write_back);
+        self
+    }
+}
+
+impl<S: Storage> Drop for CachedDB<S> {
+    fn drop(&mut self) {
+        // There's nowhere left to write to
+        if self.storage.is_removed() {
+            return;
+        }
+        self.flush().map_err(|e| eprintln!("{}", e)).ok();
+        self.persist().map_err(|e| eprintln!("{}", e)).ok();
+    }
+}
 
-        // Iter over keys and check if key is not in pairs then delete
-        let mut in_cache: Vec<String> = Vec::new();
-        for key in self.cache.content.keys() {
-            in_cache.push(key.clone());
+#[cfg(test)]
+mod tests {
+    use super::CachedDB;
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::{CachePool, GenericDatabase};
+    use std::thread;
+    use std::time::Duration;
+
+    fn cached(storage: &MemoryStorage, limit: Option<usize>) -> CachedDB<MemoryStorage> {
+        CachedDB::from_storage(storage.clone(), limit, 1000)
+    }
+
+    #[test]
+    fn reserved_names_are_plain_keys() {
+        let storage = MemoryStorage::new();
+        let mut db = CachedDB::from_storage(storage.clone(), Some(10), 10);
+        db.save("a", &1u32).unwrap();
+        db.persist().unwrap();
+        let cache = storage.get("__CACHE__").unwrap();
+
+        db.save("__CACHE__", &2u32).unwrap();
+        assert_eq!(storage.get("__CACHE__").unwrap(), cache);
+        assert_eq!(db.keys(), vec!["#__CACHE__", "a"]);
+        assert_eq!(db.load::<u32>("__CACHE__").unwrap(), 2);
+    }
+
+    #[test]
+    fn pinned_keys_stay_cached() {
+        let storage = MemoryStorage::new();
+        let mut db = cached(&storage, Some(2));
+        for key in &["a", "b", "c"] {
+            db.save(key, &1u32).unwrap();
+        }
+        for _ in 0..5 {
+            db.load::<u32>("a").unwrap();
+            db.load::<u32>("b").unwrap();
         }
-        for key in in_cache {
-            if !should_exist.contains_key(key.as_str()) {
-                self.cache.content.remove(&key);
+        db.pin("c").unwrap();
+        db.resync();
+        assert!(db.cache.content.contains_key("c"));
+        assert_eq!(cached(&storage, Some(2)).pinned(), vec!["c"]);
+
+        db.unpin("c").unwrap();
+        db.resync();
+        assert!(!db.cache.content.contains_key("c"));
+        assert!(db.pinned().is_empty());
+        assert!(db.pin("d").is_err());
+    }
+
+    #[test]
+    fn write_back_flushes_on_drop() {
+        let storage = MemoryStorage::new();
+        let mut db =
+            cached(&storage, Some(10)).with_write_back(usize::MAX, Duration::from_secs(3600));
+        db.save("a", &1u32).unwrap();
+        db.save("a", &2u32).unwrap();
+        assert!(!storage.contains("a"));
+        assert_eq!(db.load::<u32>("a").unwrap(), 2);
+        assert_eq!(db.keys(), vec!["a"]);
+
+        db.delete("a");
+        db.save("b", &3u32).unwrap();
+        drop(db);
+        assert!(!storage.contains("a"));
+        assert_eq!(cached(&storage, None).load::<u32>("b").unwrap(), 3);
+    }
+
+    #[test]
+    fn write_back_flushes_on_a_timer() {
+        let storage = MemoryStorage::new();
+        let mut db =
+            cached(&storage, Some(10)).with_write_back(usize::MAX, Duration::from_millis(20));
+        db.save("a", &1u32).unwrap();
+        for _ in 0..100 {
+            if storage.contains("a") {
+                break;
             }
+            thread::sleep(Duration::from_millis(10));
         }
+        assert!(storage.contains("a"));
     }
-    pub fn new(location: &str, cache_limit: Option<usize>, resync_every: u16) -> Self {
-        CachedDB {
-            location: String::from(location),
-            cache: Cache::new(cache_limit, resync_every),
+
+    #[test]
+    fn pool_shares_follow_usage() {
+        let pool = CachePool::new(4);
+        let (first, second) = (MemoryStorage::new(), MemoryStorage::new());
+        let mut idle = cached(&first, None).with_pool(&pool);
+        let mut busy = cached(&second, None).with_pool(&pool);
+        let keys = ["a", "b", "c", "d"];
+        for key in &keys {
+            idle.save(key, &1u32).unwrap();
+            busy.save(key, &1u32).unwrap();
+        }
+        for key in &keys {
+            idle.load::<u32>(key).unwrap();
+        }
+        idle.resync();
+        assert_eq!(idle.cache.limit, Some(4));
+        assert_eq!(idle.cache.content.len(), 4);
+
+        for _ in 0..10 {
+            for key in &keys {
+                busy.load::<u32>(key).unwrap();
+            }
         }
+        busy.resync();
+        assert_eq!(busy.cache.limit, Some(4));
+        // The idle one shrinks on its next load, without waiting for its own resync
+        idle.load::<u32>("a").unwrap();
+        assert_eq!(idle.cache.limit, Some(0));
+        assert!(idle.cache.content.is_empty());
     }
 }
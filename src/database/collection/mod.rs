@@ -0,0 +1,384 @@
+use crate::database::cached::CachedDB;
+use crate::database::indexed::IndexedDB;
+use crate::error::DBError;
+use crate::key;
+use crate::storage::{is_reserved, Storage};
+use crate::GenericDatabase;
+use serde::de::{Deserializer, IgnoredAny};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use std::any;
+use std::borrow::Borrow;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// A typed table on top of a database, where every value is a V and every key a K.
+///
+/// Keys can be anything serializable, and are encoded so that [`keys`](#method.keys) come out in
+/// the order of K itself (numbers by value, tuples field by field). Strings are used as they are,
+/// so a `Collection<String, V>` reads databases written with plain string keys.
+///
+/// Values are saved together with the name of V, so loading a key that was saved as some other
+/// type fails with an error saying so instead of a confusing decode error.
+///
+/// ```no_run
+/// use sfsdb::database::collection::Collection;
+///
+/// let db = sfsdb::new_cached("shop", Some(100), 100);
+/// let mut users = db.collection::<String>("users").unwrap();
+/// users.save("justin", &String::from("Justin")).unwrap();
+///
+/// let mut orders: Collection<(u32, u64), Vec<u32>, _> = Collection::new(sfsdb::new("orders"));
+/// orders.save(&(7, 1_548_000_000), &vec![4, 2]).unwrap();
+/// ```
+///
+/// Collections opened with `collection` live in their own subdirectory of the database they were
+/// opened from, so keys of different collections never collide. The underlying database is still
+/// reachable through `Deref`, for everything that doesn't touch values or keys such as
+/// `list_children`.
+pub struct Collection<K, V, D: GenericDatabase> {
+    db: D,
+    tag: String,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+// What's actually saved for every value
+#[derive(Serialize, Deserialize, Clone)]
+struct Tagged<V> {
+    tag: String,
+    value: V,
+}
+
+// Just the tag of a saved value, whatever its type
+#[derive(Serialize, Deserialize, Clone)]
+struct TagOnly {
+    tag: String,
+    value: Skipped,
+}
+
+#[derive(Clone)]
+struct Skipped;
+
+impl Serialize for Skipped {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_unit()
+    }
+}
+
+impl<'de> Deserialize<'de> for Skipped {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        IgnoredAny::deserialize(deserializer)?;
+        Ok(Skipped)
+    }
+}
+
+// A value saved by a collection, decoded once and shared from then on
+#[derive(Deserialize)]
+struct TaggedShared<V> {
+    tag: String,
+    value: Shared<V>,
+}
+
+struct Shared<V>(Arc<V>);
+
+impl<'de, V: Deserialize<'de>> Deserialize<'de> for Shared<V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        V::deserialize(deserializer).map(|value| Shared(Arc::new(value)))
+    }
+}
+
+impl<K, V, D: GenericDatabase> Collection<K, V, D>
+where
+    for<'de> K: Deserialize<'de> + Serialize,
+    for<'de> V: Deserialize<'de> + Serialize + Clone,
+{
+    /// Use db for values of type V under keys of type K.
+    pub fn new(db: D) -> Self {
+        Collection {
+            db,
+            tag: String::from(any::type_name::<V>()),
+            types: PhantomData,
+        }
+    }
+
+    /// Tag values with name instead of the name of V. The name of a type can change between
+    /// compiler versions and when it's moved or renamed, a fixed tag doesn't.
+    pub fn with_type_tag(mut self, name: &str) -> Self {
+        self.tag = String::from(name);
+        self
+    }
+
+    /// Use another type of key for the same values.
+    pub fn with_key_type<L>(self) -> Collection<L, V, D>
+    where
+        for<'de> L: Deserialize<'de> + Serialize,
+    {
+        Collection {
+            db: self.db,
+            tag: self.tag,
+            types: PhantomData,
+        }
+    }
+
+    /// Get the filepath of the collection.
+    pub fn location(&self) -> &str {
+        self.db.location()
+    }
+
+    /// Check if a key exists in the collection.
+    pub fn exists<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Serialize + ?Sized,
+    {
+        self.db.exists(key)
+    }
+
+    /// Save a value to the collection.
+    pub fn save<Q>(&mut self, key: &Q, value: &V) -> Result<(), DBError>
+    where
+        K: Borrow<Q>,
+        Q: Serialize + ?Sized,
+    {
+        self.db.save(
+            key,
+            &Tagged {
+                tag: self.tag.clone(),
+                value: value.clone(),
+            },
+        )
+    }
+
+    /// Load a value from the collection. Fails if it was saved as another type.
+    pub fn load<Q>(&mut self, key: &Q) -> Result<V, DBError>
+    where
+        K: Borrow<Q>,
+        Q: Serialize + ?Sized,
+    {
+        match self.db.load::<Tagged<V>>(key) {
+            Ok(tagged) if tagged.tag == self.tag => Ok(tagged.value),
+            Ok(tagged) => Err(self.mismatch(key, &tagged.tag)),
+            Err(e) => Err(self.explain(key, e)),
+        }
+    }
+
+    fn mismatch<Q: Serialize + ?Sized>(&self, key: &Q, saved: &str) -> DBError {
+        let key = key::encode(key).unwrap_or_default();
+        DBError::load(&format!("{} was saved as {}, not {}", key, saved, self.tag))
+    }
+
+    // Find out whether a value that can't be loaded is of the wrong type or just broken
+    fn explain<Q: Serialize + ?Sized>(&mut self, key: &Q, e: DBError) -> DBError {
+        match self.db.load::<TagOnly>(key) {
+            Ok(saved) if saved.tag != self.tag => self.mismatch(key, &saved.tag),
+            _ => e,
+        }
+    }
+
+    /// Remove a key/value from the collection.
+    pub fn delete<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Serialize + ?Sized,
+    {
+        self.db.delete(key)
+    }
+
+    /// All keys in the collection, in the order of K.
+    pub fn keys(&self) -> Result<Vec<K>, DBError> {
+        self.db.keys().iter().map(|k| key::decode(k)).collect()
+    }
+
+    /// Give back the database the collection is stored in.
+    pub fn into_inner(self) -> D {
+        self.db
+    }
+}
+
+impl<K, V, D: GenericDatabase> Deref for Collection<K, V, D> {
+    type Target = D;
+
+    fn deref(&self) -> &D {
+        &self.db
+    }
+}
+
+impl<K, V, S: Storage> Collection<K, V, CachedDB<S>>
+where
+    for<'de> K: Deserialize<'de> + Serialize,
+    for<'de> V: Deserialize<'de> + Serialize + Clone,
+{
+    /// Load a value, keeping the decoded value cached. See
+    /// [`CachedDB::load_shared`](../cached/struct.CachedDB.html#method.load_shared).
+    pub fn load_shared<Q>(&mut self, key: &Q) -> Result<Arc<V>, DBError>
+    where
+        K: Borrow<Q>,
+        Q: Serialize + ?Sized,
+        V: Send + Sync + 'static,
+    {
+        match self.db.load_shared::<TaggedShared<V>>(key) {
+            Ok(tagged) if tagged.tag == self.tag => Ok(tagged.value.0.clone()),
+            Ok(tagged) => Err(self.mismatch(key, &tagged.tag)),
+            Err(e) => Err(self.explain(key, e)),
+        }
+    }
+
+    /// Keep a key in the cache at all times.
+    pub fn pin<Q>(&mut self, key: &Q) -> Result<(), DBError>
+    where
+        K: Borrow<Q>,
+        Q: Serialize + ?Sized,
+    {
+        self.db.pin(key)
+    }
+
+    /// Let a pinned key be cached based on usage again.
+    pub fn unpin<Q>(&mut self, key: &Q) -> Result<(), DBError>
+    where
+        K: Borrow<Q>,
+        Q: Serialize + ?Sized,
+    {
+        self.db.unpin(key)
+    }
+
+    /// Write all saves that so far only exist in the cache to disk.
+    pub fn flush(&mut self) -> Result<(), DBError> {
+        self.db.flush()
+    }
+}
+
+impl<K, V, I, S: Storage> Collection<K, V, IndexedDB<I, S>>
+where
+    for<'de> K: Deserialize<'de> + Serialize,
+    for<'de> V: Deserialize<'de> + Serialize + Clone,
+    for<'de> I: Deserialize<'de> + Serialize + Clone,
+{
+    /// Save a value to the collection along with its index.
+    pub fn save_with_index<Q>(&mut self, key: &Q, value: &V, index: I) -> Result<(), DBError>
+    where
+        K: Borrow<Q>,
+        Q: Serialize + ?Sized,
+    {
+        self.db.save_with_index(
+            key,
+            &Tagged {
+                tag: self.tag.clone(),
+                value: value.clone(),
+            },
+            index,
+        )
+    }
+
+    /// Attach an index to key
+    pub fn add_index<Q>(&mut self, key: &Q, index: I) -> Result<(), DBError>
+    where
+        K: Borrow<Q>,
+        Q: Serialize + ?Sized,
+    {
+        self.db.add_index(key, index)
+    }
+
+    /// Get the index attached to key
+    pub fn get_index<Q>(&self, key: &Q) -> Option<&I>
+    where
+        K: Borrow<Q>,
+        Q: Serialize + ?Sized,
+    {
+        self.db.get_index(key)
+    }
+
+    /// Get the index attached to key, failing for an index kept on disk. See
+    /// [`IndexedDB::try_get_index`](../indexed/struct.IndexedDB.html#method.try_get_index).
+    pub fn try_get_index<Q>(&self, key: &Q) -> Result<Option<&I>, DBError>
+    where
+        K: Borrow<Q>,
+        Q: Serialize + ?Sized,
+    {
+        self.db.try_get_index(key)
+    }
+
+    /// Mutate an existing index
+    pub fn edit_index<Q, F>(&mut self, key: &Q, with: F) -> Result<(), DBError>
+    where
+        K: Borrow<Q>,
+        Q: Serialize + ?Sized,
+        F: FnMut(I) -> I,
+    {
+        self.db.edit_index(key, with)
+    }
+
+    /// Remove index attached to key
+    pub fn delete_index<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Serialize + ?Sized,
+    {
+        self.db.delete_index(key)
+    }
+
+    /// The keys whose index matches, in the order of K.
+    pub fn search_with<F>(&self, apply: F) -> Result<Vec<K>, DBError>
+    where
+        F: Fn(&I) -> bool,
+    {
+        let mut keys = self.db.search_with(apply);
+        keys.sort();
+        keys.iter().map(|k| key::decode(k)).collect()
+    }
+
+    /// Load a value, keeping the decoded value cached. See
+    /// [`CachedDB::load_shared`](../cached/struct.CachedDB.html#method.load_shared).
+    pub fn load_shared<Q>(&mut self, key: &Q) -> Result<Arc<V>, DBError>
+    where
+        K: Borrow<Q>,
+        Q: Serialize + ?Sized,
+        V: Send + Sync + 'static,
+    {
+        match self.db.load_shared::<TaggedShared<V>>(key) {
+            Ok(tagged) if tagged.tag == self.tag => Ok(tagged.value.0.clone()),
+            Ok(tagged) => Err(self.mismatch(key, &tagged.tag)),
+            Err(e) => Err(self.explain(key, e)),
+        }
+    }
+
+    /// Keep a key in the cache at all times.
+    pub fn pin<Q>(&mut self, key: &Q) -> Result<(), DBError>
+    where
+        K: Borrow<Q>,
+        Q: Serialize + ?Sized,
+    {
+        self.db.pin(key)
+    }
+
+    /// Let a pinned key be cached based on usage again.
+    pub fn unpin<Q>(&mut self, key: &Q) -> Result<(), DBError>
+    where
+        K: Borrow<Q>,
+        Q: Serialize + ?Sized,
+    {
+        self.db.unpin(key)
+    }
+
+    /// Write all saves that so far only exist in the cache to disk.
+    pub fn flush(&mut self) -> Result<(), DBError> {
+        self.db.flush()
+    }
+}
+
+// Collections are namespaces of the storage, which are directories for most storage
+pub(crate) fn check_name(name: &str) -> Result<(), DBError> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.contains('/')
+        || name.contains('\0')
+        || is_reserved(name)
+    {
+        return Err(DBError::key(&format!(
+            "{:?} can't be used as a collection name",
+            name
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use std::collections::BTreeMap;
+use std::iter::Sum;
+
+// The smallest (or largest, with Greater) of values, skipping those that can't be compared
+pub(crate) fn extreme<V, T>(values: T, keep: std::cmp::Ordering) -> Option<V>
+where
+    V: PartialOrd,
+    T: IntoIterator<Item = V>,
+{
+    values.into_iter().fold(None, |best, value| match best {
+        Some(best) if value.partial_cmp(&best) != Some(keep) => Some(best),
+        _ => Some(value),
+    })
+}
+
+/// The results of a [`Query`](struct.Query.html) split up into groups, see
+/// [`Query::group_by`](struct.Query.html#method.group_by). Every aggregate is per group, and
+/// groups are sorted.
+pub struct GroupBy<'a, G, I> {
+    groups: BTreeMap<G, Vec<(&'a String, &'a I)>>,
+}
+
+impl<'a, G: Ord + Clone, I> GroupBy<'a, G, I> {
+    pub(crate) fn new<T>(entries: T, group: impl Fn(&I) -> G) -> Self
+    where
+        T: IntoIterator<Item = (&'a String, &'a I)>,
+    {
+        let mut groups: BTreeMap<G, Vec<(&'a String, &'a I)>> = BTreeMap::new();
+        for (key, index) in entries {
+            groups.entry(group(index)).or_default().push((key, index));
+        }
+        GroupBy { groups }
+    }
+
+    /// The amount of results in every group.
+    pub fn count(&self) -> BTreeMap<G, usize> {
+        self.groups
+            .iter()
+            .map(|(group, entries)| (group.clone(), entries.len()))
+            .collect()
+    }
+
+    /// The sum of what extract gets out of every index of a group.
+    pub fn sum<V, F>(&self, extract: F) -> BTreeMap<G, V>
+    where
+        V: Sum<V>,
+        F: Fn(&I) -> V,
+    {
+        self.aggregate(|entries| entries.map(&extract).sum())
+    }
+
+    /// The smallest of what extract gets out of every index of a group.
+    pub fn min<V, F>(&self, extract: F) -> BTreeMap<G, V>
+    where
+        V: PartialOrd,
+        F: Fn(&I) -> V,
+    {
+        self.aggregate(|entries| extreme(entries.map(&extract), std::cmp::Ordering::Less))
+            .into_iter()
+            .filter_map(|(group, value)| value.map(|value| (group, value)))
+            .collect()
+    }
+
+    /// The largest of what extract gets out of every index of a group.
+    pub fn max<V, F>(&self, extract: F) -> BTreeMap<G, V>
+    where
+        V: PartialOrd,
+        F: Fn(&I) -> V,
+    {
+        self.aggregate(|entries| extreme(entries.map(&extract), std::cmp::Ordering::Greater))
+            .into_iter()
+            .filter_map(|(group, value)| value.map(|value| (group, value)))
+            .collect()
+    }
+
+    /// Any aggregate, computed from the indexes of every group.
+    pub fn aggregate<V, F>(&self, apply: F) -> BTreeMap<G, V>
+    where
+        F: Fn(&mut dyn Iterator<Item = &'a I>) -> V,
+    {
+        self.groups
+            .iter()
+            .map(|(group, entries)| {
+                let mut indexes = entries.iter().map(|(_, index)| *index);
+                (group.clone(), apply(&mut indexes))
+            })
+            .collect()
+    }
+
+    /// The keys of every group, sorted.
+    pub fn keys(&self) -> BTreeMap<G, Vec<String>> {
+        self.groups
+            .iter()
+            .map(|(group, entries)| {
+                let mut keys: Vec<String> = entries.iter().map(|(key, _)| (*key).clone()).collect();
+                keys.sort();
+                (group.clone(), keys)
+            })
+            .collect()
+    }
+}
@@ -1,72 +1,618 @@
 use hashbrown::HashMap;
 
+use super::secondary::{Kind, Secondaries, Secondary};
 use crate::error::DBError;
-use crate::filesystem;
+use crate::storage::{self, Storage};
+use rmp_serde::{decode, encode};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use std::str::FromStr;
+use std::collections::BTreeMap;
+use std::io::Cursor;
 
-const INDEX_FOLDER: &str = "__INDEX__";
+pub const INDEX_FOLDER: &str = "__INDEX__";
+
+// A compact index is every index at some point, followed by a log of what changed since
+const SNAPSHOT: &str = "__SNAPSHOT__";
+const LOG: &str = "__LOG__";
+// The log is folded into a new snapshot once it has more records than this, or than there are
+// indexes, so snapshots cost about as much as the records they replace
+const SNAPSHOT_AFTER: usize = 1024;
+// The names and kinds of the secondary indexes created so far
+const SECONDARY: &str = "__SECONDARY__";
 
 // TODO: Make it into a {} struct that contains both path to full and cached copy
-pub struct Index<T> {
-    pub location: PathBuf,
+pub struct Index<T, S: Storage> {
+    pub store: S,
+    // Every index, or only the hot ones when the index lives on disk
     pub mem: HashMap<String, T>,
+    // Secondary indexes by name, updated along with mem
+    pub secondary: Secondaries<T>,
+    // Every secondary index that was ever created and not dropped, including those that weren't
+    // created again since the index was opened
+    defined: BTreeMap<String, Kind>,
+    // The amount of records logged since the last snapshot, None when every index is its own key
+    logged: Option<usize>,
+    // Some when the index lives on disk rather than in mem
+    hot: Option<Hot>,
+}
+
+// Which indexes of an index living on disk are kept in mem, the least recently used ones making
+// room for others
+struct Hot {
+    capacity: usize,
+    tick: u64,
+    used: HashMap<String, u64>,
+    by_use: BTreeMap<u64, String>,
+}
+
+impl Hot {
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        if let Some(last) = self.used.insert(key.to_owned(), self.tick) {
+            self.by_use.remove(&last);
+        }
+        self.by_use.insert(self.tick, key.to_owned());
+    }
+
+    fn forget(&mut self, key: &str) {
+        if let Some(last) = self.used.remove(key) {
+            self.by_use.remove(&last);
+        }
+    }
+
+    // The least recently used key, if there are more than capacity
+    fn evict(&mut self) -> Option<String> {
+        if self.used.len() <= self.capacity {
+            return None;
+        }
+        let (_, key) = self.by_use.pop_first()?;
+        self.used.remove(&key);
+        Some(key)
+    }
 }
 
-impl<T> Index<T> {
-    pub fn new(db_root: &str) -> Self {
+impl<T, S: Storage> Index<T, S>
+where
+    for<'de> T: Deserialize<'de> + Serialize,
+{
+    // Load every index saved in store
+    pub fn new(store: S) -> Self {
+        if store.contains(SNAPSHOT) || store.contains(LOG) {
+            return Index::load_compact(store);
+        }
+        let mut mem = HashMap::new();
+        for key in store.list() {
+            match storage::load(&store, &key) {
+                Ok(v) => {
+                    mem.insert(key, v);
+                }
+                Err(_) => eprintln!("Skipping invalid index {:?}", key),
+            }
+        }
+        Index {
+            defined: definitions(&store),
+            store,
+            mem,
+            secondary: HashMap::new(),
+            logged: None,
+            hot: None,
+        }
+    }
+
+    // Leave every index in store, keeping at most hot of the most recently used ones in mem. A
+    // compact index is split up into one key each first, and kept in mem if that fails.
+    pub fn on_disk(mut store: S, hot: usize) -> Self {
+        if store.contains(SNAPSHOT) || store.contains(LOG) {
+            let mut compact = Index::load_compact(store);
+            if let Err(e) = compact.expand() {
+                eprintln!(
+                    "Keeping the compact index of {} in memory ({})",
+                    compact.store.location(),
+                    e
+                );
+                return compact;
+            }
+            store = compact.store;
+        }
         Index {
-            location: PathBuf::from_str(&format!("{}/{}/", db_root, INDEX_FOLDER)).unwrap(),
+            defined: definitions(&store),
+            store,
             mem: HashMap::new(),
+            secondary: HashMap::new(),
+            logged: None,
+            hot: Some(Hot {
+                capacity: hot,
+                tick: 0,
+                used: HashMap::new(),
+                by_use: BTreeMap::new(),
+            }),
+        }
+    }
+
+    // Read the snapshot and replay the log on top of it. Once there's a snapshot, keys of their
+    // own are left over from switching modes when it was interrupted, and are removed.
+    fn load_compact(mut store: S) -> Self {
+        let mut mem: HashMap<String, T> = HashMap::new();
+        if store.contains(SNAPSHOT) {
+            match storage::load(&store, SNAPSHOT) {
+                Ok(snapshot) => mem = snapshot,
+                Err(e) => eprintln!("Skipping invalid index snapshot ({})", e),
+            }
+        }
+        let mut logged = 0;
+        let log = store.get(LOG).unwrap_or_default();
+        let mut records = Cursor::new(&log[..]);
+        // Where the last record that could be read ends
+        let mut good = 0;
+        while good < log.len() {
+            match decode::from_read::<_, (String, Option<T>)>(&mut records) {
+                Ok((key, Some(index))) => {
+                    mem.insert(key, index);
+                }
+                Ok((key, None)) => {
+                    mem.remove(&key);
+                }
+                // A record cut short by a crash, which is always the last one. It's cut off so
+                // that records logged from now on don't end up behind it.
+                Err(_) => {
+                    eprintln!("Skipping invalid index log record");
+                    if let Err(e) = store.put_durable(LOG, &log[..good]) {
+                        eprintln!("Unable to cut the invalid record off the index log ({})", e);
+                    }
+                    break;
+                }
+            }
+            good = records.position() as usize;
+            logged += 1;
+        }
+        if store.contains(SNAPSHOT) {
+            for key in store.list() {
+                if let Err(e) = store.delete(&key) {
+                    eprintln!("Unable to remove the leftover index {:?} ({})", key, e);
+                }
+            }
+        }
+        Index {
+            defined: definitions(&store),
+            store,
+            mem,
+            secondary: HashMap::new(),
+            logged: Some(logged),
+            hot: None,
+        }
+    }
+
+    pub fn is_on_disk(&self) -> bool {
+        self.hot.is_some()
+    }
+
+    // Switch to keeping every index in a snapshot and a log instead of one key each. The snapshot
+    // is written to a temporary key and renamed, so the switch happens all at once when it's
+    // there. The keys of every index are only removed after, and are ignored from then on.
+    pub fn compact(&mut self) -> Result<(), DBError> {
+        if self.is_on_disk() {
+            return Err(DBError::index("An index kept on disk can't be compact"));
+        }
+        if self.logged.is_some() {
+            return Ok(());
+        }
+        self.snapshot(&[])?;
+        for key in self.store.list() {
+            self.store.delete(&key)?;
+        }
+        Ok(())
+    }
+
+    // Switch from a snapshot and a log back to one key each. The snapshot is only removed once
+    // every key is written, so it's never without a complete copy of the index, and the keys
+    // written so far are removed again if it's interrupted.
+    fn expand(&mut self) -> Result<(), DBError> {
+        self.snapshot(&[])?;
+        self.store.delete(LOG)?;
+        for (key, index) in self.mem.iter() {
+            storage::save(&mut self.store, key, index)?;
+        }
+        self.store.delete(SNAPSHOT)
+    }
+
+    // Write every index as a new snapshot, with a log of only records. Until the log is
+    // replaced, the old one is replayed on top of a snapshot that already has all of it, which
+    // changes nothing.
+    fn snapshot(&mut self, records: &[u8]) -> Result<(), DBError> {
+        match encode::to_vec(&self.mem) {
+            Ok(bytes) => self.store.put_durable(SNAPSHOT, &bytes)?,
+            Err(_) => return Err(DBError::save("Unable to serialize index snapshot")),
         }
+        self.store.put(LOG, records)?;
+        self.logged = Some(if records.is_empty() { 0 } else { 1 });
+        Ok(())
     }
 
-    pub fn get(&self, key: &str) -> Option<&T> {
-        self.mem.get(key)
+    // Log that key now has index (or none), taking a new snapshot when due. Records are logged
+    // before mem changes, so a snapshot keeps the record in the log.
+    fn log(&mut self, key: &str, index: Option<&T>) -> Result<(), DBError> {
+        let record = match encode::to_vec(&(key, index)) {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(DBError::save(&format!("Unable to serialize index {}", key))),
+        };
+        let logged = self.logged.unwrap_or(0) + 1;
+        if logged > SNAPSHOT_AFTER.max(self.mem.len()) {
+            return self.snapshot(&record);
+        }
+        self.store.append(LOG, &record)?;
+        self.logged = Some(logged);
+        Ok(())
     }
 
-    pub fn disk_save(&mut self, index: &T, key: &str) -> Result<(), DBError>
+    // Every index, which can only be borrowed all at once if the index doesn't live on disk
+    pub fn all(&self) -> Result<&HashMap<String, T>, DBError> {
+        if self.is_on_disk() {
+            return Err(DBError::index(&format!(
+                "The index of {} is kept on disk, so it can only be read one index at a time",
+                self.store.location()
+            )));
+        }
+        Ok(&self.mem)
+    }
+
+    // The index of key, read from disk if it isn't in mem
+    pub fn fetch(&mut self, key: &str) -> Option<T>
     where
-        T: Serialize,
+        T: Clone,
     {
-        self.location.push(key);
-        filesystem::fs_save(&self.location, index)?;
-        self.location.pop();
-        Ok(())
+        if let Some(index) = self.mem.get(key) {
+            let index = index.clone();
+            if let Some(hot) = self.hot.as_mut() {
+                hot.touch(key);
+            }
+            return Some(index);
+        }
+        let index = self.read(key)?;
+        self.remember(key, index.clone());
+        Some(index)
+    }
+
+    // Read key from store, which only has it if the index lives on disk
+    fn read(&self, key: &str) -> Option<T> {
+        if !self.is_on_disk() || !self.store.contains(key) {
+            return None;
+        }
+        match storage::load(&self.store, key) {
+            Ok(index) => Some(index),
+            Err(e) => {
+                eprintln!("Skipping invalid index {:?} ({})", key, e);
+                None
+            }
+        }
+    }
+
+    // Take the index of key out of mem (or store), leaving secondary indexes alone
+    fn take(&mut self, key: &str) -> Option<T> {
+        match self.mem.remove(key) {
+            Some(index) => {
+                if let Some(hot) = self.hot.as_mut() {
+                    hot.forget(key);
+                }
+                Some(index)
+            }
+            None => self.read(key),
+        }
     }
 
-    pub fn attach(&mut self, key: &str, index: T) {
+    fn remember(&mut self, key: &str, index: T) {
+        let hot = match self.hot.as_mut() {
+            Some(hot) => hot,
+            None => {
+                self.mem.insert(key.to_owned(), index);
+                return;
+            }
+        };
+        if hot.capacity == 0 {
+            return;
+        }
+        hot.touch(key);
         self.mem.insert(key.to_owned(), index);
+        if let Some(cold) = hot.evict() {
+            self.mem.remove(&cold);
+        }
+    }
+
+    // Every key with an index
+    pub fn keys(&self) -> Vec<String> {
+        if self.is_on_disk() {
+            return self.store.list();
+        }
+        self.mem.keys().cloned().collect()
+    }
+
+    // Go through every index, streaming them from store if the index lives on disk
+    pub fn scan<F: FnMut(&str, &T)>(&self, mut apply: F) {
+        if !self.is_on_disk() {
+            for (key, index) in self.mem.iter() {
+                apply(key, index);
+            }
+            return;
+        }
+        for key in self.store.list() {
+            match self.mem.get(&key) {
+                Some(index) => apply(&key, index),
+                None => {
+                    if let Some(index) = self.read(&key) {
+                        apply(&key, &index);
+                    }
+                }
+            }
+        }
+    }
+
+    fn disk_save(&mut self, index: &T, key: &str) -> Result<(), DBError> {
+        if self.logged.is_some() {
+            return self.log(key, Some(index));
+        }
+        storage::save(&mut self.store, key, index)
+    }
+
+    // Whether key may have index, which is up to unique constraints. Nothing may change while a
+    // unique constraint from before the index was opened isn't there to check it.
+    pub fn check(&self, key: &str, index: &T) -> Result<(), DBError> {
+        self.check_defined()?;
+        for secondary in self.secondary.values() {
+            secondary.check(key, index)?;
+        }
+        Ok(())
+    }
+
+    // Write index to disk and swap it in for old, in mem and every secondary index
+    fn replace(&mut self, key: &str, old: Option<T>, index: T) -> Result<(), DBError> {
+        if let Err(e) = self
+            .check(key, &index)
+            .and_then(|_| self.disk_save(&index, key))
+        {
+            if let Some(old) = old {
+                self.remember(key, old);
+            }
+            return Err(e);
+        }
+        for secondary in self.secondary.values_mut() {
+            if let Some(old) = &old {
+                secondary.remove(key, old);
+            }
+            secondary.insert(key, &index);
+        }
+        self.remember(key, index);
+        Ok(())
+    }
+
+    // Attach index to key, replacing the one it had
+    pub fn put(&mut self, key: &str, index: T) -> Result<(), DBError> {
+        let old = self.take(key);
+        self.replace(key, old, index)
+    }
+
+    // Swap indexes in for every index there is. They're checked against unique constraints as a
+    // whole first, so keys can trade fields, and nothing is changed if they don't pass.
+    pub fn replace_all(&mut self, indexes: HashMap<String, T>) -> Result<(), DBError> {
+        self.check_defined()?;
+        for secondary in self.secondary.values() {
+            secondary.check_all(&indexes)?;
+        }
+        let stale: Vec<String> = self
+            .keys()
+            .into_iter()
+            .filter(|key| !indexes.contains_key(key))
+            .collect();
+        for key in stale {
+            self.remove(&key)?;
+        }
+
+        // Every old index leaves the secondary indexes before a new one enters them
+        let mut swaps = Vec::with_capacity(indexes.len());
+        for (key, index) in indexes {
+            let old = self.take(&key);
+            if let Some(old) = &old {
+                for secondary in self.secondary.values_mut() {
+                    secondary.remove(&key, old);
+                }
+            }
+            swaps.push((key, old, index));
+        }
+        let mut swaps = swaps.into_iter();
+        while let Some((key, old, index)) = swaps.next() {
+            if let Err(e) = self.disk_save(&index, &key) {
+                // Whatever wasn't swapped yet keeps its old index
+                for (key, old, _) in std::iter::once((key, old, index)).chain(swaps) {
+                    if let Some(old) = old {
+                        for secondary in self.secondary.values_mut() {
+                            secondary.insert(&key, &old);
+                        }
+                        self.remember(&key, old);
+                    }
+                }
+                return Err(e);
+            }
+            for secondary in self.secondary.values_mut() {
+                secondary.insert(&key, &index);
+            }
+            self.remember(&key, index);
+        }
+        Ok(())
+    }
+
+    // Index everything there is, and from then on everything attached. Nothing is added if
+    // anything there is fails its check.
+    pub fn add_secondary(
+        &mut self,
+        name: &str,
+        mut secondary: Box<dyn Secondary<T>>,
+    ) -> Result<(), DBError> {
+        let mut failed = None;
+        self.scan(|key, index| {
+            if failed.is_some() {
+                return;
+            }
+            match secondary.check(key, index) {
+                Ok(()) => secondary.insert(key, index),
+                Err(e) => failed = Some(e),
+            }
+        });
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        self.defined.insert(name.to_owned(), secondary.kind());
+        self.secondary.insert(name.to_owned(), secondary);
+        self.save_definitions()
+    }
+
+    pub fn drop_secondary(&mut self, name: &str) -> Result<(), DBError> {
+        self.secondary.remove(name);
+        if self.defined.remove(name).is_some() {
+            return self.save_definitions();
+        }
+        Ok(())
+    }
+
+    // Secondary indexes that were created before the index was opened, but not since
+    pub fn missing(&self) -> Vec<(&str, Kind)> {
+        self.defined
+            .iter()
+            .filter(|(name, _)| !self.secondary.contains_key(*name))
+            .map(|(name, kind)| (name.as_str(), *kind))
+            .collect()
     }
 
-    pub fn update<F, I>(&mut self, key: &str, mut apply: F) -> Result<(), DBError>
+    fn check_defined(&self) -> Result<(), DBError> {
+        match self
+            .missing()
+            .into_iter()
+            .find(|(_, kind)| *kind == Kind::Unique)
+        {
+            Some((name, _)) => Err(DBError::constraint(&format!(
+                "The unique constraint {} has to be created again before indexes can change",
+                name
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn save_definitions(&mut self) -> Result<(), DBError> {
+        if self.defined.is_empty() {
+            if self.store.contains(SECONDARY) {
+                self.store.delete(SECONDARY)?;
+            }
+            return Ok(());
+        }
+        storage::save(&mut self.store, SECONDARY, &self.defined)
+    }
+
+    pub fn update<F>(&mut self, key: &str, mut apply: F) -> Result<(), DBError>
     where
-        T: Serialize,
-        for<'de> I: Deserialize<'de> + Serialize + Clone,
+        T: Clone,
         F: FnMut(T) -> T,
     {
-        match self.mem.remove(key) {
+        match self.take(key) {
             Some(index) => {
-                let new = apply(index);
-                self.disk_save(&new, key)?;
-                Ok(self.attach(key, new))
+                let new = apply(index.clone());
+                self.replace(key, Some(index), new)
             }
+            None => Err(DBError::index(&format!(
+                "key {} not found when attempting to edit its index",
+                key
+            ))),
+        }
+    }
+
+    // Detach the index of key, if it has one
+    pub fn remove(&mut self, key: &str) -> Result<(), DBError> {
+        let old = self.take(key);
+        match self.logged {
+            Some(_) if old.is_some() => self.log(key, None)?,
+            Some(_) => {}
             None => {
-                return Err(DBError::index(&format!(
-                    "key {} not found when attempting to edit its index",
-                    key
-                )));
+                if old.is_some() || self.store.contains(key) {
+                    self.store.delete(key)?;
+                }
+            }
+        }
+        if let Some(old) = old {
+            for secondary in self.secondary.values_mut() {
+                secondary.remove(key, &old);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn delete_tree(&mut self, prefix: &str) -> Result<(), DBError> {
+        let root = storage::tree_root(prefix)?;
+        let removed: Vec<String> = self
+            .keys()
+            .into_iter()
+            .filter(|key| storage::in_tree(key, root))
+            .collect();
+        for key in removed {
+            match self.logged {
+                Some(_) => self.remove(&key)?,
+                // Removed from disk all at once below
+                None => {
+                    if let Some(old) = self.take(&key) {
+                        for secondary in self.secondary.values_mut() {
+                            secondary.remove(&key, &old);
+                        }
+                    }
+                }
             }
         }
+        if self.logged.is_some() {
+            return Ok(());
+        }
+        self.store.delete_tree(root)
     }
+}
 
-    pub fn delete(&mut self, key: &str) {
-        self.mem.remove(key);
+fn definitions<S: Storage>(store: &S) -> BTreeMap<String, Kind> {
+    if !store.contains(SECONDARY) {
+        return BTreeMap::new();
     }
+    storage::load(store, SECONDARY).unwrap_or_else(|e| {
+        eprintln!("Skipping invalid secondary index definitions ({})", e);
+        BTreeMap::new()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Index, SNAPSHOT};
+    use crate::storage::{self, MemoryStorage, Storage};
+
+    #[test]
+    fn leftovers_of_compacting_stay_deleted() {
+        let mut store = MemoryStorage::new();
+        let mut index = Index::<u32, _>::new(store.clone());
+        index.put("a", 1).unwrap();
+        index.put("b", 2).unwrap();
+        index.compact().unwrap();
+        index.remove("a").unwrap();
+        // As if compacting crashed right after the snapshot
+        storage::save(&mut store, "a", &1u32).unwrap();
 
-    pub fn disk_delete(&mut self, key: &str) {
-        self.location.push(key);
-        filesystem::fs_delete(&self.location)
+        let reopened = Index::<u32, _>::new(store.clone());
+        assert_eq!(reopened.keys(), vec!["b"]);
+        assert!(store.list().is_empty());
+
+        let on_disk = Index::<u32, _>::on_disk(store.clone(), 0);
+        assert_eq!(on_disk.keys(), vec!["b"]);
+        assert!(!store.contains(SNAPSHOT));
+    }
+
+    #[test]
+    fn compact_index_reopens() {
+        let store = MemoryStorage::new();
+        let mut index = Index::<u32, _>::new(store.clone());
+        index.compact().unwrap();
+        for i in 0..3000 {
+            index.put(&(i % 100).to_string(), i).unwrap();
+        }
+        index.remove("7").unwrap();
+        let reopened = Index::<u32, _>::new(store);
+        assert_eq!(reopened.mem.len(), 99);
+        assert_eq!(reopened.mem["42"], 2942);
     }
 }
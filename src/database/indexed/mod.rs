@@ -1,117 +1,304 @@
+mod aggregate;
 mod index;
+#[cfg(feature = "parallel")]
+mod parallel;
+mod query;
+mod secondary;
 
-use crate::cache::Cache;
+pub use aggregate::GroupBy;
+pub use query::{Cursor, Query, QueryIter};
+pub use secondary::TagQuery;
+
+use crate::database::cached::CachedDB;
+use crate::database::collection::Collection;
 use crate::error::DBError;
-use crate::filesystem::*;
-use crate::GenericDatabase;
+use crate::sequence;
+use crate::storage::{FileStorage, Storage};
+use crate::{CachePool, Encoded, GenericDatabase, Key};
 use hashbrown::HashMap;
 use rmp_serde::{decode, encode};
+use secondary::{FieldIndex, TagIndex, UniqueIndex};
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use std::time::Duration;
 
-// Index will always be loaded in ram
-// but i might want to add a flag to make
-// it purely disk-saved
-pub struct IndexedDB<T>
+// The index is loaded into ram, unless it's opened with from_storage_on_disk
+pub struct IndexedDB<T, S: Storage = FileStorage>
 where
     for<'de> T: Deserialize<'de> + Serialize + Clone,
 {
-    location: String,
-    index: index::Index<T>,
-    cache: Cache,
+    db: CachedDB<S>,
+    index: index::Index<T, S>,
+    indexer: Option<Indexer<T>>,
 }
 
-impl<I> GenericDatabase for IndexedDB<I>
+// Computes the index of a key from its encoded value
+type Indexer<I> = Box<dyn Fn(&str, &[u8]) -> Result<I, DBError> + Send + Sync>;
+
+impl<I, S: Storage> GenericDatabase for IndexedDB<I, S>
 where
     for<'de> I: Deserialize<'de> + Serialize + Clone,
 {
     fn location(&self) -> &str {
-        &self.location
-    }
-    fn exists(&self, key: &str) -> bool {
-        if self.cache.limit.is_some() {
-            let mut p = PathBuf::new();
-            p.push(&self.location);
-            p.push(key);
-            p.exists()
-        } else {
-            self.cache.content.contains_key(key)
-        }
+        self.db.location()
     }
-    fn save<T>(&mut self, key: &str, value: &T) -> Result<(), DBError>
+    fn exists(&self, key: &(impl Key + ?Sized)) -> bool {
+        self.db.exists(key)
+    }
+    fn save<T>(&mut self, key: &(impl Key + ?Sized), value: &T) -> Result<(), DBError>
     where
         for<'de> T: Deserialize<'de> + Serialize + Clone,
     {
-        let mut path = PathBuf::new();
-        path.push(&self.location());
-        path.push(key);
-        fs_save(&path, &value)?;
-
-        let k = key.to_owned();
-
-        // Keep track of usage for syncing
-        self.cache.add_tracker(k.clone());
-
-        // Put everything on the cache untill it's full. And then start to resync from there
-        if !self.cache.full {
-            match self.cache.limit {
-                Some(limit) => {
-                    if self.cache.count.len() == limit {
-                        self.cache.full = true
-                    }
+        let key = key.encode_key()?;
+        // The index is computed first, so a value that can't be indexed isn't saved either
+        let index = match &self.indexer {
+            Some(indexer) => match encode::to_vec(value) {
+                Ok(bytes) => {
+                    let index = indexer(&key, &bytes)?;
+                    self.index.check(&key, &index)?;
+                    Some(index)
                 }
-                None => (),
-            }
-            self.cache.content.insert(
-                k.clone(),
-                match encode::to_vec(value) {
-                    Ok(v) => v,
-                    Err(_) => return Err(DBError::save(&format!("Unable to serialize {}", k))),
-                },
-            );
+                Err(_) => return Err(DBError::save(&format!("Unable to serialize {}", key))),
+            },
+            None => None,
+        };
+        self.db.save(&Encoded(&key), value)?;
+        if let Some(index) = index {
+            self.index.put(&key, index)?;
         }
         Ok(())
     }
-    fn load<T>(&mut self, key: &str) -> Result<T, DBError>
+    fn load<T>(&mut self, key: &(impl Key + ?Sized)) -> Result<T, DBError>
     where
-        for<'de> T: Deserialize<'de>,
+        for<'de> T: Deserialize<'de> + Serialize + Clone,
     {
-        // Perform resync once ever X amount of loads
-        if self.cache.should_resync() {
-            self.cache.timer = 0;
-            // Prevent possible saving over index
-            self.cache.full = true;
+        self.db.load(key)
+    }
+    fn delete(&mut self, key: &(impl Key + ?Sized)) {
+        self.db.delete(key);
+        self.delete_index(key);
+    }
+    fn keys(&self) -> Vec<String> {
+        self.db.keys()
+    }
+}
 
-            self.resync();
-        }
-        self.cache.timer += 1;
-        self.cache.increase_use(key);
-        match self.cache.content.get(key) {
-            None => {
-                let mut path = PathBuf::new();
-                path.push(&self.location());
-                path.push(key);
-                match fs_load::<T>(&path) {
-                    Ok(v) => Ok(v),
-                    Err(e) => Err(e),
-                }
-            }
-            Some(v) => Ok(match decode::from_slice(v) {
-                Ok(v) => v,
-                Err(e) => return Err(DBError::load(&format!("Unable to decode {} ({})", key, e))),
-            }),
+impl<I: 'static, S: Storage> IndexedDB<I, S>
+where
+    for<'de> I: Deserialize<'de> + Serialize + Clone,
+{
+    /// Keep keys sorted by a field of their index, so they can be found with
+    /// [`lookup`](#method.lookup) and [`lookup_range`](#method.lookup_range) without going
+    /// through every index like [`search_with`](#method.search_with) does. It's kept up to date
+    /// by everything that changes an index.
+    ///
+    /// Secondary indexes live in memory. They're built from the index when created, so they have
+    /// to be created again every time the database is opened. Only their names and kinds are
+    /// saved, so [`missing_indexes`](#method.missing_indexes) can tell which ones still have to
+    /// be. Creating one under a name that's already taken replaces it.
+    ///
+    /// ```no_run
+    /// # #[derive(serde::Serialize, serde::Deserialize, Clone)]
+    /// # struct User { age: u8 }
+    /// let mut db = sfsdb::new_indexed::<User>("users", None, 100);
+    /// db.create_index("age", |user: &User| user.age).unwrap();
+    /// let adults = db.lookup_range("age", 18u8..).unwrap();
+    /// ```
+    pub fn create_index<V, F>(&mut self, name: &str, extract: F) -> Result<(), DBError>
+    where
+        V: Ord + Send + Sync + 'static,
+        F: Fn(&I) -> V + Send + Sync + 'static,
+    {
+        self.index
+            .add_secondary(name, Box::new(FieldIndex::new(extract)))
+    }
+
+    /// Keep keys by every term their index has in some field, such as tags or categories, so
+    /// they can be found by term with [`tags`](#method.tags). Like
+    /// [`create_index`](#method.create_index) it only lives in memory, and has to be created
+    /// again every time the database is opened.
+    ///
+    /// ```no_run
+    /// # #[derive(serde::Serialize, serde::Deserialize, Clone)]
+    /// # struct User { categories: Vec<String> }
+    /// let mut db = sfsdb::new_indexed::<User>("users", None, 100);
+    /// db.create_tag_index("categories", |user: &User| user.categories.clone()).unwrap();
+    /// let keys = db
+    ///     .tags("categories")
+    ///     .unwrap()
+    ///     .all_of(&["programmers"])
+    ///     .none_of(&["locked"])
+    ///     .keys();
+    /// ```
+    pub fn create_tag_index<F, T>(&mut self, name: &str, extract: F) -> Result<(), DBError>
+    where
+        F: Fn(&I) -> T + Send + Sync + 'static,
+        T: IntoIterator<Item = String>,
+    {
+        self.index
+            .add_secondary(name, Box::new(TagIndex::new(extract)))
+    }
+
+    /// Make sure no two keys ever have the same field in their index, such as the email of a
+    /// user. Saving, adding or editing an index that has the same field as the index of another
+    /// key fails with a constraint error naming that key, and leaves everything as it was.
+    ///
+    /// Like [`create_index`](#method.create_index) the constraint lives in memory and has to be
+    /// created again every time the database is opened. Until it is, no index can be saved,
+    /// added, edited or reindexed, so it can't be broken by forgetting to. If keys already share
+    /// a field, it isn't created and the error names two of them.
+    ///
+    /// ```no_run
+    /// # #[derive(serde::Serialize, serde::Deserialize, Clone)]
+    /// # struct User { email: String }
+    /// let mut db = sfsdb::new_indexed::<User>("users", None, 100);
+    /// db.create_unique("email", |user: &User| user.email.clone()).unwrap();
+    /// let ferris = User { email: String::from("ferris@example.com") };
+    /// db.add_index("ferris", ferris.clone()).unwrap();
+    /// assert!(db.add_index("crab", ferris).is_err());
+    /// let key = db.find_unique("email", &String::from("ferris@example.com")).unwrap();
+    /// ```
+    pub fn create_unique<V, F>(&mut self, name: &str, extract: F) -> Result<(), DBError>
+    where
+        V: Ord + Send + Sync + 'static,
+        F: Fn(&I) -> V + Send + Sync + 'static,
+    {
+        self.index
+            .add_secondary(name, Box::new(UniqueIndex::new(name, extract)))
+    }
+
+    /// The key whose field of the unique constraint `name` equals value, if any.
+    pub fn find_unique<V>(&self, name: &str, value: &V) -> Result<Option<String>, DBError>
+    where
+        V: Ord + Send + Sync + 'static,
+    {
+        Ok(secondary::unique::<I, V>(&self.index.secondary, name)?.get(value))
+    }
+
+    /// Query the tag index `name`, starting out with every key.
+    pub fn tags(&self, name: &str) -> Result<TagQuery<'_, I>, DBError> {
+        secondary::tag(&self.index.secondary, name).map(TagQuery::new)
+    }
+
+    /// Start a query over the index. Unlike [`search_with`](#method.search_with), results
+    /// come in a fixed order and only as many indexes are looked at as it takes to get them.
+    /// Queries can also be aggregated, such as with [`Query::sum`](struct.Query.html#method.sum)
+    /// or [`Query::group_by`](struct.Query.html#method.group_by), in one pass over the index.
+    /// Queries over an index kept on disk fail when they're run.
+    ///
+    /// ```no_run
+    /// # #[derive(serde::Serialize, serde::Deserialize, Clone)]
+    /// # struct User { age: u8, name: String }
+    /// let mut db = sfsdb::new_indexed::<User>("users", None, 100);
+    /// db.create_index("age", |user: &User| user.age).unwrap();
+    /// let mut page = db
+    ///     .query()
+    ///     .lookup_range("age", 18u8..)
+    ///     .filter(|user| user.name.starts_with('A'))
+    ///     .order_by(|user| user.name.clone())
+    ///     .limit(20)
+    ///     .iter()
+    ///     .unwrap();
+    /// let users: Vec<_> = page.by_ref().collect();
+    /// let next = db
+    ///     .query()
+    ///     .lookup_range("age", 18u8..)
+    ///     .filter(|user| user.name.starts_with('A'))
+    ///     .order_by(|user| user.name.clone())
+    ///     .limit(20)
+    ///     .after(page.cursor().unwrap());
+    /// ```
+    pub fn query(&self) -> Query<'_, I> {
+        let query = Query::new(&self.index.mem, &self.index.secondary);
+        match self.index.all() {
+            Ok(_) => query,
+            Err(e) => query.fail(e),
         }
     }
-    fn delete(&mut self, key: &str) {
-        self.cache.content.remove(key);
-        self.cache.del_tracker(key);
 
-        let mut path = PathBuf::new();
-        path.push(&self.location());
-        path.push(key);
-        fs_delete(&path);
-        self.index.delete(key);
+    /// Stop maintaining a secondary index or unique constraint, for good.
+    pub fn drop_index(&mut self, name: &str) -> Result<(), DBError> {
+        self.index.drop_secondary(name)
+    }
+
+    /// The names of the secondary indexes and unique constraints that were created before the
+    /// database was opened, but not again since.
+    ///
+    /// ```no_run
+    /// # #[derive(serde::Serialize, serde::Deserialize, Clone)]
+    /// # struct User { age: u8 }
+    /// let mut db = sfsdb::new_indexed::<User>("users", None, 100);
+    /// for name in db.missing_indexes() {
+    ///     match name.as_str() {
+    ///         "age" => db.create_index("age", |user: &User| user.age).unwrap(),
+    ///         _ => db.drop_index(&name).unwrap(),
+    ///     }
+    /// }
+    /// ```
+    pub fn missing_indexes(&self) -> Vec<String> {
+        self.index
+            .missing()
+            .into_iter()
+            .map(|(name, _)| name.to_owned())
+            .collect()
+    }
+
+    /// The keys whose field of the secondary index `name` equals value, sorted.
+    pub fn lookup<V>(&self, name: &str, value: &V) -> Result<Vec<String>, DBError>
+    where
+        V: Ord + Send + Sync + 'static,
+    {
+        Ok(secondary::field::<I, V>(&self.index.secondary, name)?.get(value))
+    }
+
+    /// The keys whose field of the secondary index `name` is within range, in the order of the
+    /// field.
+    pub fn lookup_range<V, R>(&self, name: &str, range: R) -> Result<Vec<String>, DBError>
+    where
+        V: Ord + Send + Sync + 'static,
+        R: RangeBounds<V>,
+    {
+        Ok(secondary::field::<I, V>(&self.index.secondary, name)?.range(range))
+    }
+
+    /// The smallest field of the secondary index `name`, None if it's empty.
+    pub fn min_of<V>(&self, name: &str) -> Result<Option<V>, DBError>
+    where
+        V: Ord + Clone + Send + Sync + 'static,
+    {
+        Ok(secondary::field::<I, V>(&self.index.secondary, name)?.first())
+    }
+
+    /// The largest field of the secondary index `name`, None if it's empty.
+    pub fn max_of<V>(&self, name: &str) -> Result<Option<V>, DBError>
+    where
+        V: Ord + Clone + Send + Sync + 'static,
+    {
+        Ok(secondary::field::<I, V>(&self.index.secondary, name)?.last())
+    }
+
+    /// How many keys have every field of the secondary index `name`, which is a group by
+    /// that field without going through a single index.
+    ///
+    /// ```no_run
+    /// # #[derive(serde::Serialize, serde::Deserialize, Clone)]
+    /// # struct Order { country: String }
+    /// let mut db = sfsdb::new_indexed::<Order>("orders", None, 100);
+    /// db.create_index("country", |order: &Order| order.country.clone()).unwrap();
+    /// let per_country = db.count_by::<String>("country").unwrap();
+    /// ```
+    pub fn count_by<V>(&self, name: &str) -> Result<BTreeMap<V, usize>, DBError>
+    where
+        V: Ord + Clone + Send + Sync + 'static,
+    {
+        Ok(secondary::field::<I, V>(&self.index.secondary, name)?.counts())
+    }
+
+    /// How many keys have every term of the tag index `name`.
+    pub fn count_tags(&self, name: &str) -> Result<BTreeMap<String, usize>, DBError> {
+        Ok(secondary::tag(&self.index.secondary, name)?.counts())
     }
 }
 
@@ -119,142 +306,526 @@ impl<I> IndexedDB<I>
 where
     for<'de> I: Deserialize<'de> + Serialize + Clone,
 {
-    // Manually perform a resync of the cache. This will cache the top N most used keys.
-    // This is already run automatically on a schedule.
+    pub(crate) fn new(location: &str, cache_limit: Option<usize>, resync_every: u16) -> Self {
+        IndexedDB::from_storage(FileStorage::new(location), cache_limit, resync_every)
+    }
+
+    pub(crate) fn new_on_disk(
+        location: &str,
+        cache_limit: Option<usize>,
+        resync_every: u16,
+        hot_indexes: Option<usize>,
+    ) -> Self {
+        IndexedDB::from_storage_on_disk(
+            FileStorage::new(location),
+            cache_limit,
+            resync_every,
+            hot_indexes,
+        )
+    }
+}
+
+impl<I, S: Storage> IndexedDB<I, S>
+where
+    for<'de> I: Deserialize<'de> + Serialize + Clone,
+{
+    /// Manually perform a resync of the cache. This will cache the top N most used keys.
+    /// This is already run automatically on a schedule.
     pub fn resync(&mut self) {
-        let mut pairs = Vec::with_capacity(self.cache.count.len());
-        let mut i: usize = 0;
-        for (key, value) in &self.cache.count {
-            pairs.push((key, value));
-            i += 1;
-        }
+        self.db.resync()
+    }
 
-        // Sort by one of tuple
-        pairs.sort_by(|(_, a_v), (_, b_v)| b_v.cmp(a_v));
-
-        let mut should_exist: HashMap<&str, ()> = HashMap::new();
-        // Iter of those that are supposed to be in new cache
-        for (c, (k, _)) in pairs.iter().enumerate() {
-            // Stop conditionals
-            if c > i {
-                break;
-            } else if self.cache.limit.is_some() {
-                if c >= self.cache.limit.unwrap() {
-                    break;
-                };
-            }
+    /// Share the cache limit with other databases through a pool. See
+    /// [`CachePool`](../../struct.CachePool.html).
+    pub fn with_pool(mut self, pool: &CachePool) -> Self {
+        self.db = self.db.with_pool(pool);
+        self
+    }
 
-            should_exist.insert(k.clone(), ());
-            let value_from_fs = if self.cache.content.contains_key(*k) {
-                continue;
-            } else {
-                let mut path = PathBuf::new();
-                path.push(&self.location);
-                path.push(*k);
-                fs::read(path)
-                    .map_err(|e| eprintln!("sfsdb: File and Cache mismatch ({}): {}", *k, e))
-                    .unwrap_or_default()
-            };
-            self.cache
-                .content
-                .insert(k.clone().to_owned(), value_from_fs);
-        }
+    /// Write all saves that so far only exist in the cache to disk.
+    pub fn flush(&mut self) -> Result<(), DBError> {
+        self.db.flush()
+    }
+
+    /// Keep a bloom filter of all keys in memory. See
+    /// [`SimpleDB::with_bloom_filter`](../simple/struct.SimpleDB.html#method.with_bloom_filter).
+    pub fn with_bloom_filter(mut self, expected_keys: usize) -> Self {
+        self.db = self.db.with_bloom_filter(expected_keys);
+        self
+    }
+
+    /// Remember up to `capacity` keys that were looked up but don't exist.
+    pub fn with_negative_cache(mut self, capacity: usize) -> Self {
+        self.db = self.db.with_negative_cache(capacity);
+        self
+    }
+
+    /// Keep a key in the cache at all times. See
+    /// [`CachedDB::pin`](../cached/struct.CachedDB.html#method.pin).
+    pub fn pin(&mut self, key: &(impl Key + ?Sized)) -> Result<(), DBError> {
+        self.db.pin(key)
+    }
+
+    /// Let a pinned key be cached based on usage again.
+    pub fn unpin(&mut self, key: &(impl Key + ?Sized)) -> Result<(), DBError> {
+        self.db.unpin(key)
+    }
+
+    /// All currently pinned keys.
+    pub fn pinned(&self) -> Vec<String> {
+        self.db.pinned()
+    }
+
+    /// Load a value of type T, keeping the decoded value cached. See
+    /// [`CachedDB::load_shared`](../cached/struct.CachedDB.html#method.load_shared).
+    pub fn load_shared<T>(&mut self, key: &(impl Key + ?Sized)) -> Result<Arc<T>, DBError>
+    where
+        for<'de> T: Deserialize<'de> + Send + Sync + 'static,
+    {
+        self.db.load_shared(key)
+    }
+
+    /// Write the usage statistics of the cache to the database directory. See
+    /// [`CachedDB::persist`](../cached/struct.CachedDB.html#method.persist).
+    pub fn persist(&mut self) -> Result<(), DBError> {
+        self.db.persist()
+    }
+
+    /// Eagerly load the top N most used keys (from previous runs) into the cache.
+    pub fn with_warm_up(mut self, top: usize) -> Self {
+        self.db = self.db.with_warm_up(top);
+        self
+    }
 
-        // Iter over keys and check if key is not in pairs then delete
-        let mut in_cache: Vec<String> = Vec::new();
-        for key in self.cache.content.keys() {
-            in_cache.push(key.clone());
+    /// A typed collection stored in its own subdirectory, with its own index type J and the same
+    /// cache limit (or pool) and resync schedule as this database. See
+    /// [`Collection`](../collection/struct.Collection.html).
+    pub fn collection<T, J>(
+        &self,
+        name: &str,
+    ) -> Result<Collection<String, T, IndexedDB<J, S>>, DBError>
+    where
+        for<'de> T: Deserialize<'de> + Serialize + Clone,
+        for<'de> J: Deserialize<'de> + Serialize + Clone,
+    {
+        let (cache_limit, resync_every) = self.db.cache_settings();
+        let mut db =
+            IndexedDB::from_storage(self.db.child_storage(name)?, cache_limit, resync_every);
+        if let Some(pool) = self.db.pool() {
+            db = db.with_pool(&pool);
         }
-        for key in in_cache {
-            if !should_exist.contains_key(key.as_str()) {
-                self.cache.content.remove(&key);
+        Ok(Collection::new(db))
+    }
+
+    /// A typed collection stored in its own subdirectory, with its own index type J and cache
+    /// settings.
+    pub fn collection_with<T, J>(
+        &self,
+        name: &str,
+        cache_limit: Option<usize>,
+        resync_every: u16,
+    ) -> Result<Collection<String, T, IndexedDB<J, S>>, DBError>
+    where
+        for<'de> T: Deserialize<'de> + Serialize + Clone,
+        for<'de> J: Deserialize<'de> + Serialize + Clone,
+    {
+        Ok(Collection::new(IndexedDB::from_storage(
+            self.db.child_storage(name)?,
+            cache_limit,
+            resync_every,
+        )))
+    }
+
+    /// The names directly below prefix when keys are read as `/` separated paths. See
+    /// [`Storage::list_children`](../../storage/trait.Storage.html#method.list_children).
+    pub fn list_children(&self, prefix: &str) -> Vec<String> {
+        self.db.list_children(prefix)
+    }
+
+    /// Remove prefix and every key below it, along with their indexes.
+    pub fn delete_tree(&mut self, prefix: &str) -> Result<(), DBError> {
+        self.db.delete_tree(prefix)?;
+        self.index.delete_tree(prefix)
+    }
+
+    /// The next number of a sequence, starting at 1. See
+    /// [`SimpleDB::next_id`](../simple/struct.SimpleDB.html#method.next_id).
+    pub fn next_id(&mut self, sequence: &str) -> Result<u64, DBError> {
+        self.db.next_id(sequence)
+    }
+
+    /// Save a value under the next number of the sequence `id`, which is returned.
+    pub fn insert<T>(&mut self, value: &T) -> Result<u64, DBError>
+    where
+        for<'de> T: Deserialize<'de> + Serialize + Clone,
+    {
+        let id = self.next_id(sequence::INSERT_SEQUENCE)?;
+        self.save(&id, value)?;
+        Ok(id)
+    }
+
+    /// Save a value along with its index under the next number of the sequence `id`, which is
+    /// returned.
+    pub fn insert_with_index<T>(&mut self, value: &T, index: I) -> Result<u64, DBError>
+    where
+        for<'de> T: Deserialize<'de> + Serialize + Clone,
+    {
+        let id = self.next_id(sequence::INSERT_SEQUENCE)?;
+        self.save_with_index(&id, value, index)?;
+        Ok(id)
+    }
+
+    /// Save a value under a new [`ulid`](../../fn.ulid.html), which is returned.
+    pub fn insert_ulid<T>(&mut self, value: &T) -> Result<String, DBError>
+    where
+        for<'de> T: Deserialize<'de> + Serialize + Clone,
+    {
+        let id = sequence::ulid();
+        self.save(&id, value)?;
+        Ok(id)
+    }
+
+    /// Save a value along with its index under a new [`ulid`](../../fn.ulid.html), which is
+    /// returned.
+    pub fn insert_ulid_with_index<T>(&mut self, value: &T, index: I) -> Result<String, DBError>
+    where
+        for<'de> T: Deserialize<'de> + Serialize + Clone,
+    {
+        let id = sequence::ulid();
+        self.save_with_index(&id, value, index)?;
+        Ok(id)
+    }
+
+    /// Compute the index of every value saved with
+    /// [`save`](../../trait.GenericDatabase.html#tymethod.save) (or inserted) with indexer,
+    /// instead of passing one along with [`save_with_index`](#method.save_with_index). Saving a
+    /// value that isn't a T is an error. Indexes that are passed along explicitly are still used
+    /// as they are.
+    ///
+    /// Values saved before the indexer was set (or with a different one) keep their index until
+    /// [`reindex`](#method.reindex) is called.
+    ///
+    /// ```no_run
+    /// use sfsdb::GenericDatabase;
+    ///
+    /// # #[derive(serde::Serialize, serde::Deserialize, Clone)]
+    /// # struct User { name: String, age: u8 }
+    /// let mut db = sfsdb::new_indexed::<u8>("users", None, 100).with_indexer(|user: &User| user.age);
+    /// db.save("ferris", &User { name: String::from("Ferris"), age: 9 }).unwrap();
+    /// assert_eq!(db.get_index("ferris"), Some(&9));
+    /// ```
+    pub fn with_indexer<T, F>(mut self, indexer: F) -> Self
+    where
+        for<'de> T: Deserialize<'de> + 'static,
+        F: Fn(&T) -> I + Send + Sync + 'static,
+    {
+        self.indexer = Some(Box::new(move |key, bytes| {
+            match decode::from_slice::<T>(bytes) {
+                Ok(value) => Ok(indexer(&value)),
+                Err(e) => Err(DBError::index(&format!("Unable to index {} ({})", key, e))),
             }
+        }));
+        self
+    }
+
+    /// Rebuild every index from the values saved with the indexer set by
+    /// [`with_indexer`](#method.with_indexer), and remove indexes of keys without a value. Unique
+    /// constraints are checked against all of the new indexes at once, so keys may trade fields.
+    /// If any value can't be indexed or the new indexes break a constraint, nothing is changed.
+    pub fn reindex(&mut self) -> Result<(), DBError> {
+        let indexer = match &self.indexer {
+            Some(indexer) => indexer,
+            None => return Err(DBError::index("There's no indexer to reindex with")),
+        };
+        let mut indexes = HashMap::new();
+        for key in self.db.keys() {
+            let index = indexer(&key, &self.db.load_bytes(&key)?)?;
+            indexes.insert(key, index);
         }
+        self.index.replace_all(indexes)
     }
 
     /// Save a value of type T to the database along with an index of any type
-    pub fn save_with_index<T>(&mut self, key: &str, data: &T, index: I) -> Result<(), DBError>
+    pub fn save_with_index<T>(
+        &mut self,
+        key: &(impl Key + ?Sized),
+        data: &T,
+        index: I,
+    ) -> Result<(), DBError>
     where
         for<'de> T: Deserialize<'de> + Serialize + Clone,
     {
-        self.save(key, data)?;
-        self.index.disk_save(&index, key)?;
-        Ok(self.index.attach(key, index))
+        let key = key.encode_key()?;
+        // Checked before anything is saved, so a violation leaves the value as it was too
+        self.index.check(&key, &index)?;
+        self.db.save(&Encoded(&key), data)?;
+        self.index.put(&key, index)
     }
 
     /// Attach an index to key
-    pub fn add_index(&mut self, key: &str, index: I) -> Result<(), DBError> {
-        if key == "" {
-            return Err(DBError::index("Empty key"));
-        }
-        self.index.disk_save(&index, key)?;
-        Ok(self.index.attach(key, index))
+    pub fn add_index(&mut self, key: &(impl Key + ?Sized), index: I) -> Result<(), DBError> {
+        self.index.put(&key.encode_key()?, index)
+    }
+
+    /// Get the index attached to key. Always None for an index kept on disk (see
+    /// [`from_storage_on_disk`](#method.from_storage_on_disk)), use
+    /// [`load_index`](#method.load_index) for those instead.
+    pub fn get_index(&self, key: &(impl Key + ?Sized)) -> Option<&I> {
+        self.try_get_index(key).ok().flatten()
+    }
+
+    /// Like [`get_index`](#method.get_index), but fails for an index kept on disk instead of
+    /// returning None.
+    pub fn try_get_index(&self, key: &(impl Key + ?Sized)) -> Result<Option<&I>, DBError> {
+        Ok(self.index.all()?.get(&key.encode_key()?))
     }
 
-    /// Get the index attached to key
-    pub fn get_index(&self, key: &str) -> Option<&I> {
-        self.index.get(key)
+    /// Get a copy of the index attached to key, reading it from disk if the index is kept on
+    /// disk and it isn't in memory.
+    pub fn load_index(&mut self, key: &(impl Key + ?Sized)) -> Option<I> {
+        self.index.fetch(&key.encode_key().ok()?)
     }
 
     /// Mutate an existing index
-    pub fn edit_index<F>(&mut self, key: &str, with: F) -> Result<(), DBError>
+    pub fn edit_index<F>(&mut self, key: &(impl Key + ?Sized), with: F) -> Result<(), DBError>
     where
         F: FnMut(I) -> I,
     {
-        self.index.update::<F, I>(key, with)?;
+        self.index.update(&key.encode_key()?, with)?;
         Ok(())
     }
 
+    /// Keep the whole index in a single snapshot and a log of changes since, instead of a file
+    /// for every key. Saving an index only appends to the log, and opening the database reads
+    /// the snapshot and the log from start to end rather than every file. The log is folded into
+    /// a new snapshot once it grows about as large as the index.
+    ///
+    /// Databases are opened in whichever mode they were in last, so this only needs to be done
+    /// once. Doing it again does nothing.
+    ///
+    /// ```no_run
+    /// let mut db = sfsdb::new_indexed::<u32>("scores", None, 100);
+    /// db.compact_index().unwrap();
+    /// ```
+    pub fn compact_index(&mut self) -> Result<(), DBError> {
+        self.index.compact()
+    }
+
     /// Remove index attached to key
-    pub fn delete_index(&mut self, key: &str) {
-        if key == "" {
-            return;
-        }
-        self.index.disk_delete(key);
-        self.index.delete(key);
+    pub fn delete_index(&mut self, key: &(impl Key + ?Sized)) {
+        let key = match key.encode_key() {
+            Ok(key) => key,
+            Err(_) => return,
+        };
+        self.index.remove(&key).map_err(|e| eprintln!("{}", e)).ok();
     }
 
     /// Dispatch a query with a closure that returns true or false using the index, depending on if it's a match or
-    /// not.
+    /// not. An index kept on disk is read from disk one index at a time.
     pub fn search_with<F>(&self, apply: F) -> Vec<String>
     where
         F: Fn(&I) -> bool,
     {
         let mut results = Vec::new();
-        for (k, v) in self.index.mem.iter() {
-            if apply(&v) {
-                results.push((*k).clone());
+        self.index.scan(|k, v| {
+            if apply(v) {
+                results.push(k.to_owned());
             };
-        }
-        return results;
+        });
+        results
     }
 
-    pub(crate) fn new(location: &str, cache_limit: Option<usize>, resync_every: u16) -> Self {
-        // Load existing fs index
-        let mut index = index::Index::new(location);
-
-        if !index.location.exists() {
-            fs::create_dir_all(&index.location).unwrap();
-        } else {
-            // List all in directory
-            // Index::disk_load(&self.location, K) for each
-            for dir in fs::read_dir(&index.location).unwrap() {
-                let p = dir.unwrap();
-                let v = match fs_load(&p.path()) {
-                    Ok(v) => v,
-                    Err(_) => {
-                        println!("Skipping invalid index {:?}", &p.path());
-                        continue;
-                    }
-                };
-                index.attach(p.file_name().to_str().unwrap(), v)
-            }
+    /// Like [`search_with`](#method.search_with), but with the index of every match along with
+    /// its key, sorted by key. Fails for an index kept on disk, use
+    /// [`search_load`](#method.search_load) for those instead.
+    pub fn search_entries<F>(&self, apply: F) -> Result<Vec<(String, &I)>, DBError>
+    where
+        F: Fn(&I) -> bool,
+    {
+        let mut results: Vec<(String, &I)> = self
+            .index
+            .all()?
+            .iter()
+            .filter(|(_, v)| apply(v))
+            .map(|(k, v)| (k.clone(), v))
+            .collect();
+        results.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(results)
+    }
+
+    /// Like [`search_with`](#method.search_with), but with the value of every match along with
+    /// its key, sorted by key. Cached values are used as they are, the rest are read from
+    /// storage together (see [`CachedDB::load_many`](../cached/struct.CachedDB.html#method.load_many)).
+    ///
+    /// ```no_run
+    /// # #[derive(serde::Serialize, serde::Deserialize, Clone)]
+    /// # struct User { age: u8 }
+    /// let mut db = sfsdb::new_indexed::<u8>("users", None, 100);
+    /// let adults: Vec<(String, User)> = db.search_load(|age| *age >= 18).unwrap();
+    /// ```
+    pub fn search_load<T, F>(&mut self, apply: F) -> Result<Vec<(String, T)>, DBError>
+    where
+        for<'de> T: Deserialize<'de>,
+        F: Fn(&I) -> bool,
+    {
+        let mut keys = self.search_with(apply);
+        keys.sort_unstable();
+        let encoded: Vec<Encoded> = keys.iter().map(|key| Encoded(key)).collect();
+        let values = self.db.load_many(&encoded)?;
+        Ok(keys.into_iter().zip(values).collect())
+    }
+
+    /// Use any storage backend instead of a directory. The index is kept in a namespace of it.
+    pub fn from_storage(storage: S, cache_limit: Option<usize>, resync_every: u16) -> Self {
+        // Load existing index
+        let index = index::Index::new(index_storage(&storage));
+
+        IndexedDB {
+            db: CachedDB::from_storage(storage, cache_limit, resync_every),
+            index,
+            indexer: None,
         }
+    }
+
+    /// Like [`from_storage`](#method.from_storage), but the index is left on disk instead of
+    /// being loaded into memory, for indexes that don't fit in it. Up to `hot_indexes` of the
+    /// most recently used indexes are kept in memory, and [`search_with`](#method.search_with)
+    /// reads the rest from disk one at a time.
+    ///
+    /// Only what works on indexes that are read as they're needed is available for all of the
+    /// index: [`load_index`](#method.load_index), [`search_with`](#method.search_with),
+    /// [`search_load`](#method.search_load) and secondary indexes, which are built by reading
+    /// every index once. Whatever borrows indexes ([`try_get_index`](#method.try_get_index),
+    /// [`search_entries`](#method.search_entries) and [`query`](#method.query) with everything
+    /// it aggregates) fails with an index error instead, and
+    /// [`get_index`](#method.get_index) finds nothing.
+    ///
+    /// A compact index (see [`compact_index`](#method.compact_index)) is split up into a file
+    /// for every key first. If that fails, it's kept in memory as it was.
+    ///
+    /// ```no_run
+    /// use sfsdb::database::indexed::IndexedDB;
+    /// use sfsdb::storage::FileStorage;
+    ///
+    /// let storage = FileStorage::new("scores");
+    /// let db = IndexedDB::<u32, _>::from_storage_on_disk(storage, None, 100, Some(10_000));
+    /// let high = db.search_with(|score| *score > 9000);
+    /// ```
+    pub fn from_storage_on_disk(
+        storage: S,
+        cache_limit: Option<usize>,
+        resync_every: u16,
+        hot_indexes: Option<usize>,
+    ) -> Self {
+        let index = index::Index::on_disk(index_storage(&storage), hot_indexes.unwrap_or(0));
 
         IndexedDB {
-            index: index,
-            cache: Cache::new(cache_limit, resync_every),
-            location: String::from(location),
+            db: CachedDB::from_storage(storage, cache_limit, resync_every),
+            index,
+            indexer: None,
+        }
+    }
+}
+
+impl<I, S: Storage + Send + 'static> IndexedDB<I, S>
+where
+    for<'de> I: Deserialize<'de> + Serialize + Clone,
+{
+    /// Switch to write-back mode for values. Indexes are still written immediately. See
+    /// [`CachedDB::with_write_back`](../cached/struct.CachedDB.html#method.with_write_back).
+    pub fn with_write_back(mut self, max_dirty_bytes: usize, interval: Duration) -> Self {
+        self.db = self.db.with_write_back(max_dirty_bytes, interval);
+        self
+    }
+}
+
+// The namespace the index is kept in, which is reserved and never taken by a collection
+fn index_storage<S: Storage>(storage: &S) -> S {
+    storage.namespace(index::INDEX_FOLDER).unwrap_or_else(|e| {
+        panic!(
+            "sfsdb: Could not open the index of {} ({})",
+            storage.location(),
+            e
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IndexedDB;
+    use crate::storage::MemoryStorage;
+    use crate::GenericDatabase;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    struct User {
+        email: String,
+    }
+
+    fn user(email: &str) -> User {
+        User {
+            email: String::from(email),
         }
     }
+
+    fn open(storage: &MemoryStorage) -> IndexedDB<User, MemoryStorage> {
+        let mut db =
+            IndexedDB::from_storage(storage.clone(), None, 100).with_indexer(|u: &User| u.clone());
+        db.create_unique("email", |u: &User| u.email.clone())
+            .unwrap();
+        db
+    }
+
+    // Change values behind the back of the index, as if they were saved without an indexer
+    fn overwrite(storage: &MemoryStorage, key: &str, email: &str) {
+        let mut raw = IndexedDB::<User, _>::from_storage(storage.clone(), None, 100);
+        raw.save(key, &user(email)).unwrap();
+        // Unique constraints that weren't created again keep indexes from changing
+        assert!(raw.add_index(key, user(email)).is_err());
+    }
+
+    #[test]
+    fn unique_violations_change_nothing() {
+        let storage = MemoryStorage::new();
+        let mut db = open(&storage);
+        db.save("a", &user("x")).unwrap();
+        db.save("b", &user("y")).unwrap();
+        assert!(db.save("c", &user("x")).is_err());
+        assert!(!db.exists("c"));
+        assert!(db.edit_index("b", |_| user("x")).is_err());
+        assert_eq!(db.get_index("b"), Some(&user("y")));
+        assert_eq!(
+            db.find_unique("email", &String::from("x")).unwrap(),
+            Some(String::from("a"))
+        );
+    }
+
+    #[test]
+    fn reindex_swaps_fields_or_rolls_back() {
+        let storage = MemoryStorage::new();
+        let mut db = open(&storage);
+        db.save("a", &user("x")).unwrap();
+        db.save("b", &user("y")).unwrap();
+        drop(db);
+
+        overwrite(&storage, "a", "y");
+        overwrite(&storage, "b", "x");
+        let mut db = open(&storage);
+        db.reindex().unwrap();
+        assert_eq!(
+            db.find_unique("email", &String::from("x")).unwrap(),
+            Some(String::from("b"))
+        );
+        drop(db);
+
+        overwrite(&storage, "c", "x");
+        let mut db = open(&storage);
+        assert!(db.reindex().is_err());
+        assert_eq!(
+            db.find_unique("email", &String::from("x")).unwrap(),
+            Some(String::from("b"))
+        );
+        assert_eq!(db.get_index("a"), Some(&user("y")));
+        assert_eq!(db.get_index("c"), None);
+    }
 }
@@ -0,0 +1,50 @@
+use super::IndexedDB;
+use crate::storage::Storage;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+impl<I: Sync, S: Storage> IndexedDB<I, S>
+where
+    for<'de> I: Deserialize<'de> + Serialize + Clone,
+{
+    /// Like [`search_with`](#method.search_with), but the index is scanned on every core at
+    /// once. Only worth it for large indexes, since splitting up the work isn't free. The keys
+    /// come in no particular order, which can differ between calls; use
+    /// [`par_search_sorted`](#method.par_search_sorted) when that matters. An index kept on disk
+    /// is searched like [`search_with`](#method.search_with) does.
+    ///
+    /// Only available with the `parallel` feature.
+    ///
+    /// ```no_run
+    /// let db = sfsdb::new_indexed::<u32>("scores", None, 100);
+    /// let high = db.par_search_with(|score| *score > 9000);
+    /// ```
+    pub fn par_search_with<F>(&self, apply: F) -> Vec<String>
+    where
+        F: Fn(&I) -> bool + Sync,
+    {
+        // Reading from disk is done one index at a time either way
+        if self.index.is_on_disk() {
+            return self.search_with(apply);
+        }
+        self.index
+            .mem
+            .par_iter()
+            .filter(|(_, v)| apply(v))
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    /// Like [`par_search_with`](#method.par_search_with), but the keys are sorted, so the
+    /// same index always gives the same result.
+    ///
+    /// Only available with the `parallel` feature.
+    pub fn par_search_sorted<F>(&self, apply: F) -> Vec<String>
+    where
+        F: Fn(&I) -> bool + Sync,
+    {
+        let mut keys = self.par_search_with(apply);
+        keys.par_sort_unstable();
+        keys
+    }
+}
@@ -0,0 +1,422 @@
+use super::aggregate::{self, GroupBy};
+use super::secondary::{self, Secondaries, TagQuery};
+use crate::error::DBError;
+use crate::key;
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::iter::Sum;
+use std::ops::RangeBounds;
+
+type Filter<'a, I> = Box<dyn Fn(&I) -> bool + 'a>;
+type Order<'a, I> = Box<dyn Fn(&I) -> Result<String, DBError> + 'a>;
+
+/// Where a page of a [`Query`](struct.Query.html) ended, to start the next page right after it
+/// with [`after`](struct.Query.html#method.after). It can be serialized to hand it out to
+/// whoever asks for the next page.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    // The encoded order_by value, if the query was ordered
+    order: Option<String>,
+    key: String,
+}
+
+/// A query over the index of an [`IndexedDB`](struct.IndexedDB.html), see
+/// [`IndexedDB::query`](struct.IndexedDB.html#method.query). Nothing is done until
+/// [`iter`](#method.iter) is called.
+///
+/// Results are ordered by key unless [`order_by`](#method.order_by) is used, and keys sharing
+/// the same order are ordered by key, so the order is always the same for the same index.
+pub struct Query<'a, I> {
+    mem: &'a HashMap<String, I>,
+    secondary: &'a Secondaries<I>,
+    // Keys narrowed down by secondary indexes, None meaning every key
+    candidates: Option<BTreeSet<String>>,
+    filters: Vec<Filter<'a, I>>,
+    order: Option<Order<'a, I>>,
+    descending: bool,
+    offset: usize,
+    limit: Option<usize>,
+    after: Option<Cursor>,
+    // The first error of a predicate, returned by iter
+    error: Option<DBError>,
+}
+
+impl<'a, I: 'static> Query<'a, I> {
+    pub(crate) fn new(mem: &'a HashMap<String, I>, secondary: &'a Secondaries<I>) -> Self {
+        Query {
+            mem,
+            secondary,
+            candidates: None,
+            filters: Vec::new(),
+            order: None,
+            descending: false,
+            offset: 0,
+            limit: None,
+            after: None,
+            error: None,
+        }
+    }
+
+    // Make iter (and everything else that runs the query) fail with e
+    pub(crate) fn fail(mut self, e: DBError) -> Self {
+        self.error.get_or_insert(e);
+        self
+    }
+
+    fn narrow(mut self, keys: Result<BTreeSet<String>, DBError>) -> Self {
+        match keys {
+            Ok(keys) => {
+                self.candidates = Some(match self.candidates.take() {
+                    Some(candidates) => candidates.intersection(&keys).cloned().collect(),
+                    None => keys,
+                })
+            }
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+        self
+    }
+
+    /// Only indexes matching apply. Filters are only run on keys left over by every indexed
+    /// predicate, and only until enough results are found.
+    pub fn filter<F>(mut self, apply: F) -> Self
+    where
+        F: Fn(&I) -> bool + 'a,
+    {
+        self.filters.push(Box::new(apply));
+        self
+    }
+
+    /// Only keys whose field of the secondary index `name` equals value.
+    pub fn lookup<V>(self, name: &str, value: &V) -> Self
+    where
+        V: Ord + Send + Sync + 'static,
+    {
+        let keys = secondary::field::<I, V>(self.secondary, name).map(|index| index.get(value));
+        self.narrow(keys)
+    }
+
+    /// Only keys whose field of the secondary index `name` is within range.
+    pub fn lookup_range<V, R>(self, name: &str, range: R) -> Self
+    where
+        V: Ord + Send + Sync + 'static,
+        R: RangeBounds<V>,
+    {
+        let keys = secondary::field::<I, V>(self.secondary, name).map(|index| index.range(range));
+        self.narrow(keys)
+    }
+
+    /// Only keys matched by terms of the tag index `name`.
+    ///
+    /// ```no_run
+    /// # #[derive(serde::Serialize, serde::Deserialize, Clone)]
+    /// # struct User { categories: Vec<String> }
+    /// # let db = sfsdb::new_indexed::<User>("users", None, 100);
+    /// let query = db
+    ///     .query()
+    ///     .tagged("categories", |tags| tags.any_of(&["admins", "moderators"]));
+    /// ```
+    pub fn tagged<F>(self, name: &str, narrow: F) -> Self
+    where
+        F: FnOnce(TagQuery<'a, I>) -> TagQuery<'a, I>,
+    {
+        let keys = secondary::tag(self.secondary, name)
+            .map(|index| narrow(TagQuery::new(index)).into_set());
+        self.narrow(keys)
+    }
+
+    /// Order results by what order extracts out of their index, using the same order as
+    /// [keys](../../trait.Key.html) do.
+    pub fn order_by<V, F>(mut self, order: F) -> Self
+    where
+        V: Serialize,
+        F: Fn(&I) -> V + 'a,
+    {
+        self.order = Some(Box::new(move |index| key::order(&order(index))));
+        self
+    }
+
+    /// Results from last to first.
+    pub fn descending(mut self) -> Self {
+        self.descending = true;
+        self
+    }
+
+    /// Skip the first n results.
+    pub fn offset(mut self, n: usize) -> Self {
+        self.offset = n;
+        self
+    }
+
+    /// At most n results.
+    pub fn limit(mut self, n: usize) -> Self {
+        self.limit = Some(n);
+        self
+    }
+
+    /// Only results after cursor, which came from
+    /// [`QueryIter::cursor`](struct.QueryIter.html#method.cursor) of the same query.
+    pub fn after(mut self, cursor: Cursor) -> Self {
+        self.after = Some(cursor);
+        self
+    }
+
+    /// Run the query. Only the keys (and their order, if ordered) are sorted up front, filters
+    /// are applied while iterating.
+    pub fn iter(self) -> Result<QueryIter<'a, I>, DBError> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let mem = self.mem;
+        let found: Vec<(&'a String, &'a I)> = match &self.candidates {
+            Some(keys) => keys
+                .iter()
+                .filter_map(|key| mem.get_key_value(key))
+                .collect(),
+            None => mem.iter().collect(),
+        };
+        let mut entries = Vec::with_capacity(found.len());
+        for (key, index) in found {
+            let order = match &self.order {
+                Some(order) => Some(order(index)?),
+                None => None,
+            };
+            entries.push((order, key, index));
+        }
+        entries.sort_unstable_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+        if self.descending {
+            entries.reverse();
+        }
+
+        let start = match &self.after {
+            Some(cursor) => {
+                let after = (&cursor.order, &cursor.key);
+                let descending = self.descending;
+                entries.partition_point(|(order, key, _)| {
+                    if descending {
+                        (order, *key) >= after
+                    } else {
+                        (order, *key) <= after
+                    }
+                })
+            }
+            None => 0,
+        };
+        entries.drain(..start);
+
+        Ok(QueryIter {
+            entries: entries.into_iter(),
+            filters: self.filters,
+            skip: self.offset,
+            left: self.limit,
+            last: None,
+        })
+    }
+
+    // Every result in no particular order, which saves sorting unless the query pages through
+    // its results
+    fn scan(self) -> Result<Box<dyn Iterator<Item = (&'a String, &'a I)> + 'a>, DBError> {
+        if self.offset > 0 || self.limit.is_some() || self.after.is_some() {
+            let mut results = self.iter()?;
+            return Ok(Box::new(std::iter::from_fn(move || results.next_entry())));
+        }
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let filters = self.filters;
+        let entries: Box<dyn Iterator<Item = (&'a String, &'a I)> + 'a> = match self.candidates {
+            Some(keys) => {
+                let mem = self.mem;
+                Box::new(
+                    keys.into_iter()
+                        .filter_map(move |key| mem.get_key_value(&key)),
+                )
+            }
+            None => Box::new(self.mem.iter()),
+        };
+        Ok(Box::new(entries.filter(move |(_, index)| {
+            filters.iter().all(|apply| apply(index))
+        })))
+    }
+
+    /// The amount of results. Queries narrowed down by secondary indexes alone are counted
+    /// without looking at a single index.
+    pub fn count(self) -> Result<usize, DBError> {
+        if self.filters.is_empty() && self.after.is_none() && self.error.is_none() {
+            let all = match &self.candidates {
+                Some(keys) => keys.len(),
+                None => self.mem.len(),
+            };
+            let all = all.saturating_sub(self.offset);
+            return Ok(self.limit.map_or(all, |limit| all.min(limit)));
+        }
+        Ok(self.scan()?.count())
+    }
+
+    /// The sum of what extract gets out of every result.
+    pub fn sum<V, F>(self, extract: F) -> Result<V, DBError>
+    where
+        V: Sum<V>,
+        F: Fn(&I) -> V,
+    {
+        Ok(self.scan()?.map(|(_, index)| extract(index)).sum())
+    }
+
+    /// The smallest of what extract gets out of every result, None without results.
+    pub fn min<V, F>(self, extract: F) -> Result<Option<V>, DBError>
+    where
+        V: PartialOrd,
+        F: Fn(&I) -> V,
+    {
+        let values = self.scan()?.map(|(_, index)| extract(index));
+        Ok(aggregate::extreme(values, Ordering::Less))
+    }
+
+    /// The largest of what extract gets out of every result, None without results.
+    pub fn max<V, F>(self, extract: F) -> Result<Option<V>, DBError>
+    where
+        V: PartialOrd,
+        F: Fn(&I) -> V,
+    {
+        let values = self.scan()?.map(|(_, index)| extract(index));
+        Ok(aggregate::extreme(values, Ordering::Greater))
+    }
+
+    /// Split the results up into groups by what group extracts out of them, to aggregate every
+    /// group on its own.
+    ///
+    /// ```no_run
+    /// # #[derive(serde::Serialize, serde::Deserialize, Clone)]
+    /// # struct Order { country: String, total: u64 }
+    /// # let db = sfsdb::new_indexed::<Order>("orders", None, 100);
+    /// let totals = db
+    ///     .query()
+    ///     .group_by(|order| order.country.clone())
+    ///     .unwrap()
+    ///     .sum(|order| order.total);
+    /// ```
+    pub fn group_by<G, F>(self, group: F) -> Result<GroupBy<'a, G, I>, DBError>
+    where
+        G: Ord + Clone,
+        F: Fn(&I) -> G,
+    {
+        Ok(GroupBy::new(self.scan()?, group))
+    }
+}
+
+/// The results of a [`Query`](struct.Query.html), as keys and their indexes.
+pub struct QueryIter<'a, I> {
+    entries: std::vec::IntoIter<(Option<String>, &'a String, &'a I)>,
+    filters: Vec<Filter<'a, I>>,
+    skip: usize,
+    left: Option<usize>,
+    last: Option<Cursor>,
+}
+
+impl<'a, I> QueryIter<'a, I> {
+    fn next_entry(&mut self) -> Option<(&'a String, &'a I)> {
+        if self.left == Some(0) {
+            return None;
+        }
+        for (order, key, index) in self.entries.by_ref() {
+            if !self.filters.iter().all(|apply| apply(index)) {
+                continue;
+            }
+            if self.skip > 0 {
+                self.skip -= 1;
+                continue;
+            }
+            if let Some(left) = &mut self.left {
+                *left -= 1;
+            }
+            self.last = Some(Cursor {
+                order,
+                key: key.clone(),
+            });
+            return Some((key, index));
+        }
+        None
+    }
+
+    /// Where the results returned so far end, to continue from there with
+    /// [`Query::after`](struct.Query.html#method.after). None if nothing was returned yet.
+    pub fn cursor(&self) -> Option<Cursor> {
+        self.last.clone()
+    }
+}
+
+impl<'a, I> Iterator for QueryIter<'a, I> {
+    type Item = (String, &'a I);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().map(|(key, index)| (key.clone(), index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::indexed::IndexedDB;
+    use crate::storage::MemoryStorage;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Clone)]
+    struct User {
+        name: String,
+        age: u8,
+    }
+
+    fn users() -> IndexedDB<User, MemoryStorage> {
+        let mut db = IndexedDB::from_storage(MemoryStorage::new(), None, 100);
+        for (key, name, age) in &[
+            ("1", "b", 30),
+            ("2", "", 20),
+            ("3", "a", 40),
+            ("4", "c", 20),
+        ] {
+            let user = User {
+                name: name.to_string(),
+                age: *age,
+            };
+            db.add_index(*key, user).unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn orders_empty_values_first() {
+        let db = users();
+        let keys: Vec<String> = db
+            .query()
+            .order_by(|user| user.name.clone())
+            .iter()
+            .unwrap()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec!["2", "3", "1", "4"]);
+        assert_eq!(db.query().order_by(|_| ()).iter().unwrap().count(), 4);
+    }
+
+    #[test]
+    fn pages_with_cursors() {
+        let db = users();
+        let mut pages = Vec::new();
+        let mut cursor = None;
+        loop {
+            let mut query = db.query().order_by(|user| user.age).descending().limit(3);
+            if let Some(cursor) = cursor.take() {
+                query = query.after(cursor);
+            }
+            let mut results = query.iter().unwrap();
+            let page: Vec<String> = results.by_ref().map(|(key, _)| key).collect();
+            if page.is_empty() {
+                break;
+            }
+            pages.push(page);
+            cursor = results.cursor();
+        }
+        assert_eq!(pages, vec![vec!["3", "1", "4"], vec!["2"]]);
+    }
+}
@@ -0,0 +1,389 @@
+use crate::error::DBError;
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+use std::any::{self, Any};
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter::FromIterator;
+use std::ops::RangeBounds;
+
+// Gets whatever a secondary index is on out of an index
+type Extractor<I, V> = Box<dyn Fn(&I) -> V + Send + Sync>;
+
+// What a secondary index is, which is all that's saved of it since extractors are code
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Kind {
+    Field,
+    Unique,
+    Tag,
+}
+
+// An index over some part of every index, kept up to date as indexes change
+pub trait Secondary<I>: Send + Sync {
+    fn kind(&self) -> Kind;
+    // Whether key may have index, checked before it's inserted
+    fn check(&self, _key: &str, _index: &I) -> Result<(), DBError> {
+        Ok(())
+    }
+    // Whether indexes may replace every index there is
+    fn check_all(&self, _indexes: &HashMap<String, I>) -> Result<(), DBError> {
+        Ok(())
+    }
+    fn insert(&mut self, key: &str, index: &I);
+    fn remove(&mut self, key: &str, index: &I);
+    fn as_any(&self) -> &dyn Any;
+}
+
+// Secondary indexes by name
+pub type Secondaries<I> = HashMap<String, Box<dyn Secondary<I>>>;
+
+fn named<'a, I>(secondary: &'a Secondaries<I>, name: &str) -> Result<&'a dyn Any, DBError> {
+    match secondary.get(name) {
+        Some(index) => Ok(index.as_any()),
+        None => Err(DBError::index(&format!(
+            "There's no secondary index {}",
+            name
+        ))),
+    }
+}
+
+pub fn field<'a, I: 'static, V: Ord + Send + Sync + 'static>(
+    secondary: &'a Secondaries<I>,
+    name: &str,
+) -> Result<&'a FieldIndex<I, V>, DBError> {
+    named(secondary, name)?
+        .downcast_ref::<FieldIndex<I, V>>()
+        .ok_or_else(|| {
+            DBError::index(&format!(
+                "Secondary index {} isn't on fields of type {}",
+                name,
+                any::type_name::<V>()
+            ))
+        })
+}
+
+pub fn unique<'a, I: 'static, V: Ord + Send + Sync + 'static>(
+    secondary: &'a Secondaries<I>,
+    name: &str,
+) -> Result<&'a UniqueIndex<I, V>, DBError> {
+    named(secondary, name)?
+        .downcast_ref::<UniqueIndex<I, V>>()
+        .ok_or_else(|| {
+            DBError::index(&format!(
+                "{} isn't a unique constraint on fields of type {}",
+                name,
+                any::type_name::<V>()
+            ))
+        })
+}
+
+pub fn tag<'a, I: 'static>(
+    secondary: &'a Secondaries<I>,
+    name: &str,
+) -> Result<&'a TagIndex<I>, DBError> {
+    named(secondary, name)?
+        .downcast_ref::<TagIndex<I>>()
+        .ok_or_else(|| DBError::index(&format!("{} isn't a tag index", name)))
+}
+
+// Keys by a field extracted from their index
+pub struct FieldIndex<I, V> {
+    extract: Extractor<I, V>,
+    tree: BTreeMap<V, BTreeSet<String>>,
+}
+
+impl<I, V: Ord> FieldIndex<I, V> {
+    pub fn new<F>(extract: F) -> Self
+    where
+        F: Fn(&I) -> V + Send + Sync + 'static,
+    {
+        FieldIndex {
+            extract: Box::new(extract),
+            tree: BTreeMap::new(),
+        }
+    }
+
+    pub fn get<C: FromIterator<String>>(&self, value: &V) -> C {
+        match self.tree.get(value) {
+            Some(keys) => keys.iter().cloned().collect(),
+            None => C::from_iter(None),
+        }
+    }
+
+    pub fn range<R: RangeBounds<V>, C: FromIterator<String>>(&self, range: R) -> C {
+        self.tree
+            .range(range)
+            .flat_map(|(_, keys)| keys.iter().cloned())
+            .collect()
+    }
+}
+
+impl<I, V: Ord + Clone> FieldIndex<I, V> {
+    pub fn first(&self) -> Option<V> {
+        self.tree.keys().next().cloned()
+    }
+
+    pub fn last(&self) -> Option<V> {
+        self.tree.keys().next_back().cloned()
+    }
+
+    pub fn counts(&self) -> BTreeMap<V, usize> {
+        self.tree
+            .iter()
+            .map(|(value, keys)| (value.clone(), keys.len()))
+            .collect()
+    }
+}
+
+impl<I: 'static, V: Ord + Send + Sync + 'static> Secondary<I> for FieldIndex<I, V> {
+    fn kind(&self) -> Kind {
+        Kind::Field
+    }
+
+    fn insert(&mut self, key: &str, index: &I) {
+        self.tree
+            .entry((self.extract)(index))
+            .or_default()
+            .insert(key.to_owned());
+    }
+
+    fn remove(&mut self, key: &str, index: &I) {
+        let value = (self.extract)(index);
+        if let Some(keys) = self.tree.get_mut(&value) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.tree.remove(&value);
+            }
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// The one key of every field extracted from their index, refusing a second one
+pub struct UniqueIndex<I, V> {
+    name: String,
+    extract: Extractor<I, V>,
+    tree: BTreeMap<V, String>,
+}
+
+impl<I, V: Ord> UniqueIndex<I, V> {
+    pub fn new<F>(name: &str, extract: F) -> Self
+    where
+        F: Fn(&I) -> V + Send + Sync + 'static,
+    {
+        UniqueIndex {
+            name: name.to_owned(),
+            extract: Box::new(extract),
+            tree: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, value: &V) -> Option<String> {
+        self.tree.get(value).cloned()
+    }
+
+    fn violation(&self, key: &str, other: &str) -> DBError {
+        DBError::constraint(&format!(
+            "{} can't have the same {} as {}",
+            key, self.name, other
+        ))
+    }
+}
+
+impl<I: 'static, V: Ord + Send + Sync + 'static> Secondary<I> for UniqueIndex<I, V> {
+    fn kind(&self) -> Kind {
+        Kind::Unique
+    }
+
+    fn check(&self, key: &str, index: &I) -> Result<(), DBError> {
+        match self.tree.get(&(self.extract)(index)) {
+            Some(other) if other != key => Err(self.violation(key, other)),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_all(&self, indexes: &HashMap<String, I>) -> Result<(), DBError> {
+        let mut seen = BTreeMap::new();
+        for (key, index) in indexes {
+            if let Some(other) = seen.insert((self.extract)(index), key) {
+                return Err(self.violation(key, other));
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, key: &str, index: &I) {
+        self.tree.insert((self.extract)(index), key.to_owned());
+    }
+
+    fn remove(&mut self, key: &str, index: &I) {
+        let value = (self.extract)(index);
+        if self.tree.get(&value).map(String::as_str) == Some(key) {
+            self.tree.remove(&value);
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// Keys by every term extracted from their index, such as tags
+pub struct TagIndex<I> {
+    extract: Extractor<I, Vec<String>>,
+    postings: BTreeMap<String, BTreeSet<String>>,
+    // Every indexed key, including those without any terms
+    all: BTreeSet<String>,
+}
+
+impl<I> TagIndex<I> {
+    pub fn new<F, T>(extract: F) -> Self
+    where
+        F: Fn(&I) -> T + Send + Sync + 'static,
+        T: IntoIterator<Item = String>,
+    {
+        TagIndex {
+            extract: Box::new(move |index| extract(index).into_iter().collect()),
+            postings: BTreeMap::new(),
+            all: BTreeSet::new(),
+        }
+    }
+
+    pub fn counts(&self) -> BTreeMap<String, usize> {
+        self.postings
+            .iter()
+            .map(|(term, keys)| (term.clone(), keys.len()))
+            .collect()
+    }
+
+    fn has(&self, term: &str, key: &str) -> bool {
+        match self.postings.get(term) {
+            Some(keys) => keys.contains(key),
+            None => false,
+        }
+    }
+}
+
+/// Keys of a tag index narrowed down by terms, see
+/// [`IndexedDB::tags`](struct.IndexedDB.html#method.tags). Every call narrows down the keys
+/// matched so far, so the first call should be the most selective one.
+pub struct TagQuery<'a, I> {
+    index: &'a TagIndex<I>,
+    // None until narrowed down, meaning every key
+    keys: Option<BTreeSet<String>>,
+}
+
+impl<'a, I> TagQuery<'a, I> {
+    pub(crate) fn new(index: &'a TagIndex<I>) -> Self {
+        TagQuery { index, keys: None }
+    }
+
+    /// Only keys with at least one of terms.
+    pub fn any_of(mut self, terms: &[&str]) -> Self {
+        self.keys = Some(match self.keys.take() {
+            Some(keys) => keys
+                .into_iter()
+                .filter(|key| terms.iter().any(|term| self.index.has(term, key)))
+                .collect(),
+            None => terms
+                .iter()
+                .filter_map(|term| self.index.postings.get(*term))
+                .flat_map(|keys| keys.iter().cloned())
+                .collect(),
+        });
+        self
+    }
+
+    /// Only keys with every one of terms.
+    pub fn all_of(mut self, terms: &[&str]) -> Self {
+        let keys = match self.keys.take() {
+            Some(keys) => keys,
+            None => {
+                // Starting from the rarest term means the fewest keys to check
+                let rarest = terms
+                    .iter()
+                    .map(|term| self.index.postings.get(*term))
+                    .min_by_key(|keys| keys.map(BTreeSet::len).unwrap_or(0));
+                match rarest {
+                    Some(Some(keys)) => keys.clone(),
+                    Some(None) => BTreeSet::new(),
+                    None => self.index.all.clone(),
+                }
+            }
+        };
+        self.keys = Some(
+            keys.into_iter()
+                .filter(|key| terms.iter().all(|term| self.index.has(term, key)))
+                .collect(),
+        );
+        self
+    }
+
+    /// Only keys with none of terms.
+    pub fn none_of(mut self, terms: &[&str]) -> Self {
+        let keys = self.keys.take().unwrap_or_else(|| self.index.all.clone());
+        self.keys = Some(
+            keys.into_iter()
+                .filter(|key| !terms.iter().any(|term| self.index.has(term, key)))
+                .collect(),
+        );
+        self
+    }
+
+    pub(crate) fn into_set(self) -> BTreeSet<String> {
+        match self.keys {
+            Some(keys) => keys,
+            None => self.index.all.clone(),
+        }
+    }
+
+    /// The matching keys, sorted.
+    pub fn keys(self) -> Vec<String> {
+        match self.keys {
+            Some(keys) => keys.into_iter().collect(),
+            None => self.index.all.iter().cloned().collect(),
+        }
+    }
+
+    /// The amount of matching keys.
+    pub fn count(&self) -> usize {
+        match &self.keys {
+            Some(keys) => keys.len(),
+            None => self.index.all.len(),
+        }
+    }
+}
+
+impl<I: 'static> Secondary<I> for TagIndex<I> {
+    fn kind(&self) -> Kind {
+        Kind::Tag
+    }
+
+    fn insert(&mut self, key: &str, index: &I) {
+        for term in (self.extract)(index) {
+            self.postings
+                .entry(term)
+                .or_default()
+                .insert(key.to_owned());
+        }
+        self.all.insert(key.to_owned());
+    }
+
+    fn remove(&mut self, key: &str, index: &I) {
+        for term in (self.extract)(index) {
+            if let Some(keys) = self.postings.get_mut(&term) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+        self.all.remove(key);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
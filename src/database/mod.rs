@@ -1,3 +1,4 @@
 pub mod cached;
+pub mod collection;
 pub mod indexed;
 pub mod simple;
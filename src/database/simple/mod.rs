@@ -1,59 +1,161 @@
-use crate::filesystem::{fs_delete, fs_load, fs_save};
+use crate::database::collection::{self, Collection};
+use crate::presence::Presence;
+use crate::sequence;
+use crate::storage::{self, FileStorage, Storage};
 use serde::{Deserialize, Serialize};
 
 use crate::error::DBError;
-use crate::GenericDatabase;
-use std::fs;
-use std::path::{Path, PathBuf};
+use crate::{GenericDatabase, Key};
 
-pub struct SimpleDB {
-    location: String,
+pub struct SimpleDB<S: Storage = FileStorage> {
+    storage: S,
+    presence: Presence,
 }
 
-impl GenericDatabase for SimpleDB {
+impl<S: Storage> GenericDatabase for SimpleDB<S> {
     fn location(&self) -> &str {
-        &self.location
-    }
-    fn exists(&self, identifier: &str) -> bool {
-        let mut p = PathBuf::new();
-        p.push(self.location());
-        p.push(identifier);
-        p.exists()
-    }
-    fn save<T: Serialize>(&mut self, key: &str, value: &T) -> Result<(), DBError> {
-        let mut path = PathBuf::new();
-        path.push(&self.location());
-        path.push(key);
-        fs_save(&path, &value)?;
+        self.storage.location()
+    }
+    fn exists(&self, key: &(impl Key + ?Sized)) -> bool {
+        let key = match key.encode_key() {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        if self.presence.absent(&key) {
+            return false;
+        }
+        self.storage.contains(&key)
+    }
+    fn save<T: Serialize>(&mut self, key: &(impl Key + ?Sized), value: &T) -> Result<(), DBError> {
+        let key = key.encode_key()?;
+        storage::save(&mut self.storage, &key, value)?;
+        self.presence.saved(&key);
+        self.maintain_presence();
         Ok(())
     }
-    fn load<T>(&mut self, key: &str) -> Result<T, DBError>
+    fn load<T>(&mut self, key: &(impl Key + ?Sized)) -> Result<T, DBError>
     where
         for<'de> T: Deserialize<'de>,
     {
-        let mut path = PathBuf::new();
-        path.push(&self.location());
-        path.push(key);
-        let v = fs_load::<T>(&path)?;
-        Ok(v)
-    }
-    fn delete(&mut self, key: &str) {
-        let mut path = PathBuf::new();
-        path.push(&self.location());
-        path.push(key);
-        fs_delete(&path);
+        let key = key.encode_key()?;
+        if self.presence.absent(&key) {
+            return Err(DBError::load(&format!("{} does not exist", key)));
+        }
+        let v = storage::load::<T, S>(&self.storage, &key);
+        if v.is_err() && !self.storage.contains(&key) {
+            self.presence.missed(&key);
+        }
+        v
+    }
+    fn delete(&mut self, key: &(impl Key + ?Sized)) {
+        let key = match key.encode_key() {
+            Ok(key) => key,
+            Err(e) => return eprintln!("{}", e),
+        };
+        self.storage
+            .delete(&key)
+            .map_err(|e| eprintln!("{}", e))
+            .ok();
+        self.presence.deleted(&key);
+        self.maintain_presence();
+    }
+    fn keys(&self) -> Vec<String> {
+        let mut keys = self.storage.list();
+        keys.sort();
+        keys
     }
 }
 
 impl SimpleDB {
     pub fn new(location: &str) -> Self {
-        if !Path::new(location).exists() {
-            if fs::create_dir(location).is_err() {
-                panic!("sfsdb: Could not create database at {}", location)
-            }
-        }
+        SimpleDB::from_storage(FileStorage::new(location))
+    }
+}
+
+impl<S: Storage> SimpleDB<S> {
+    /// Use any storage backend instead of a directory.
+    pub fn from_storage(storage: S) -> Self {
         SimpleDB {
-            location: String::from(location),
+            storage,
+            presence: Presence::default(),
+        }
+    }
+
+    /// The next number of a sequence, starting at 1. Numbers are written to disk before they're
+    /// handed out, so the same number is never handed out twice, not even after a crash.
+    /// Sequences are stored in the database directory under their name, which can contain
+    /// letters, digits, `-` and `_`.
+    pub fn next_id(&mut self, sequence: &str) -> Result<u64, DBError> {
+        sequence::next_id(&mut self.storage, sequence)
+    }
+
+    /// Save a value under the next number of the sequence `id`, which is returned.
+    pub fn insert<T>(&mut self, value: &T) -> Result<u64, DBError>
+    where
+        for<'de> T: Deserialize<'de> + Serialize + Clone,
+    {
+        let id = self.next_id(sequence::INSERT_SEQUENCE)?;
+        self.save(&id, value)?;
+        Ok(id)
+    }
+
+    /// Save a value under a new [`ulid`](../../fn.ulid.html), which is returned. Keys inserted
+    /// this way sort by when they were inserted.
+    pub fn insert_ulid<T>(&mut self, value: &T) -> Result<String, DBError>
+    where
+        for<'de> T: Deserialize<'de> + Serialize + Clone,
+    {
+        let id = sequence::ulid();
+        self.save(&id, value)?;
+        Ok(id)
+    }
+
+    /// A typed collection stored in its own subdirectory. See
+    /// [`Collection`](../collection/struct.Collection.html).
+    pub fn collection<T>(&self, name: &str) -> Result<Collection<String, T, SimpleDB<S>>, DBError>
+    where
+        for<'de> T: Deserialize<'de> + Serialize + Clone,
+    {
+        collection::check_name(name)?;
+        Ok(Collection::new(SimpleDB::from_storage(
+            self.storage.namespace(name)?,
+        )))
+    }
+
+    /// The names directly below prefix when keys are read as `/` separated paths. See
+    /// [`Storage::list_children`](../../storage/trait.Storage.html#method.list_children).
+    pub fn list_children(&self, prefix: &str) -> Vec<String> {
+        self.storage.list_children(prefix)
+    }
+
+    /// Remove prefix and every key below it.
+    pub fn delete_tree(&mut self, prefix: &str) -> Result<(), DBError> {
+        self.storage.delete_tree(prefix)
+    }
+
+    /// Keep a bloom filter of all keys in memory, so that `exists` and `load` on keys that were
+    /// never saved can be answered without touching the file system. The filter is built from
+    /// the keys currently on disk, and rebuilt every now and then to forget deleted keys.
+    ///
+    /// Keys saved by other processes after the filter was built won't be found.
+    pub fn with_bloom_filter(mut self, expected_keys: usize) -> Self {
+        let keys = self.storage.list();
+        self.presence
+            .enable_bloom(expected_keys, keys.iter().map(String::as_str));
+        self
+    }
+
+    fn maintain_presence(&mut self) {
+        if self.presence.should_rebuild() {
+            let keys = self.storage.list();
+            self.presence.rebuild(keys.iter().map(String::as_str));
         }
     }
+
+    /// Remember up to `capacity` keys that were looked up but don't exist, so that looking them
+    /// up again doesn't touch the file system.
+    pub fn with_negative_cache(mut self, capacity: usize) -> Self {
+        self.presence.enable_negative_cache(capacity);
+        self
+    }
 }
@@ -40,6 +40,12 @@ impl DBError {
     pub fn index(cause: &str) -> Self {
         Self::new("index", cause)
     }
+    pub fn key(cause: &str) -> Self {
+        Self::new("key", cause)
+    }
+    pub fn constraint(cause: &str) -> Self {
+        Self::new("constraint", cause)
+    }
 }
 
 impl Error for DBError {}
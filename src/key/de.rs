@@ -0,0 +1,348 @@
+use super::KeyError;
+use crate::storage::is_reserved;
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+
+type Result<T> = std::result::Result<T, KeyError>;
+
+pub struct Decoder<'a> {
+    encoded: &'a str,
+    // The hex of encoded, decoded on first use since strings are used as they are
+    bytes: Option<Vec<u8>>,
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(encoded: &'a str) -> Self {
+        Decoder {
+            encoded,
+            bytes: None,
+            pos: 0,
+        }
+    }
+
+    // Everything of the key has to have been read
+    pub fn finish(&mut self) -> Result<()> {
+        match &self.bytes {
+            Some(bytes) if self.pos != bytes.len() => Err(KeyError(String::from("trailing bytes"))),
+            _ => Ok(()),
+        }
+    }
+
+    fn nested(&self) -> bool {
+        self.bytes.is_some()
+    }
+
+    // Anything but a plain string means the key was encoded, and strings within it are escaped
+    fn nest(&mut self) -> Result<()> {
+        if self.bytes.is_none() {
+            let hex = match self.encoded.strip_prefix(super::ENCODED) {
+                Some(hex) if !hex.starts_with(super::ENCODED) => hex,
+                _ => return Err(KeyError(String::from("not an encoded key"))),
+            };
+            self.bytes = Some(from_hex(hex)?);
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        self.nest()?;
+        let bytes = self.bytes.as_ref().unwrap();
+        if self.pos + n > bytes.len() {
+            return Err(KeyError(String::from("unexpected end")));
+        }
+        self.pos += n;
+        Ok(&bytes[self.pos - n..self.pos])
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn escaped(&mut self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        loop {
+            match self.u8()? {
+                0 => match self.u8()? {
+                    0 => return Ok(out),
+                    0xff => out.push(0),
+                    _ => return Err(KeyError(String::from("invalid escape"))),
+                },
+                b => out.push(b),
+            }
+        }
+    }
+
+    // Whether a sequence or map has another element
+    fn more(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(KeyError(String::from("invalid marker"))),
+        }
+    }
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return Err(KeyError(String::from("not an encoded key")));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(hex.get(i..i + 2).unwrap_or("-"), 16)
+                .map_err(|_| KeyError(String::from("not an encoded key")))
+        })
+        .collect()
+}
+
+impl<'de, 'a, 'b> de::Deserializer<'de> for &'b mut Decoder<'a> {
+    type Error = KeyError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value> {
+        Err(KeyError(String::from(
+            "keys can only be decoded as a known type",
+        )))
+    }
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.u8()? {
+            0 => visitor.visit_bool(false),
+            1 => visitor.visit_bool(true),
+            _ => Err(KeyError(String::from("invalid bool"))),
+        }
+    }
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i8((self.u8()? ^ 0x80) as i8)
+    }
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i16((u16::from_be_bytes(self.array()?) ^ 0x8000) as i16)
+    }
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i32((u32::from_be_bytes(self.array()?) ^ 0x8000_0000) as i32)
+    }
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i64((u64::from_be_bytes(self.array()?) ^ 0x8000_0000_0000_0000) as i64)
+    }
+    fn deserialize_i128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i128((u128::from_be_bytes(self.array()?) ^ (1 << 127)) as i128)
+    }
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u8(self.u8()?)
+    }
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u16(u16::from_be_bytes(self.array()?))
+    }
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u32(u32::from_be_bytes(self.array()?))
+    }
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u64(u64::from_be_bytes(self.array()?))
+    }
+    fn deserialize_u128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u128(u128::from_be_bytes(self.array()?))
+    }
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let bits = u32::from_be_bytes(self.array()?);
+        visitor.visit_f32(f32::from_bits(if bits >> 31 == 1 {
+            bits ^ (1 << 31)
+        } else {
+            !bits
+        }))
+    }
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let bits = u64::from_be_bytes(self.array()?);
+        visitor.visit_f64(f64::from_bits(if bits >> 63 == 1 {
+            bits ^ (1 << 63)
+        } else {
+            !bits
+        }))
+    }
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match char::from_u32(u32::from_be_bytes(self.array()?)) {
+            Some(c) => visitor.visit_char(c),
+            None => Err(KeyError(String::from("invalid char"))),
+        }
+    }
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_string(visitor)
+    }
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if !self.nested() {
+            // The whole key is a string, used as it is unless it had to be told apart from an
+            // encoded key or a name of sfsdb
+            self.bytes = Some(Vec::new());
+            return match self.encoded.strip_prefix(super::ENCODED) {
+                None => visitor.visit_str(self.encoded),
+                Some(raw) if raw.starts_with(super::ENCODED) || is_reserved(raw) => {
+                    visitor.visit_str(raw)
+                }
+                Some(_) => Err(KeyError(String::from("not a string key"))),
+            };
+        }
+        match String::from_utf8(self.escaped()?) {
+            Ok(s) => visitor.visit_string(s),
+            Err(_) => Err(KeyError(String::from("invalid utf-8"))),
+        }
+    }
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_byte_buf(visitor)
+    }
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_byte_buf(self.escaped()?)
+    }
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.more()? {
+            visitor.visit_some(self)
+        } else {
+            visitor.visit_none()
+        }
+    }
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.nest()?;
+        visitor.visit_seq(Marked(self))
+    }
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        self.nest()?;
+        visitor.visit_seq(Fixed(self, len))
+    }
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.nest()?;
+        visitor.visit_seq(Fixed(self, len))
+    }
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.nest()?;
+        visitor.visit_map(Marked(self))
+    }
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.nest()?;
+        visitor.visit_seq(Fixed(self, fields.len()))
+    }
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.nest()?;
+        visitor.visit_enum(self)
+    }
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_u32(visitor)
+    }
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_any(visitor)
+    }
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+// Sequences and maps, where every element is preceded by 01 and the end is 00
+struct Marked<'b, 'a>(&'b mut Decoder<'a>);
+
+impl<'de, 'a, 'b> de::SeqAccess<'de> for Marked<'b, 'a> {
+    type Error = KeyError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if !self.0.more()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.0).map(Some)
+    }
+}
+
+impl<'de, 'a, 'b> de::MapAccess<'de> for Marked<'b, 'a> {
+    type Error = KeyError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if !self.0.more()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.0).map(Some)
+    }
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        seed.deserialize(&mut *self.0)
+    }
+}
+
+// Tuples and structs, which have a known amount of fields
+struct Fixed<'b, 'a>(&'b mut Decoder<'a>, usize);
+
+impl<'de, 'a, 'b> de::SeqAccess<'de> for Fixed<'b, 'a> {
+    type Error = KeyError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.1 == 0 {
+            return Ok(None);
+        }
+        self.1 -= 1;
+        seed.deserialize(&mut *self.0).map(Some)
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.1)
+    }
+}
+
+impl<'de, 'a, 'b> de::EnumAccess<'de> for &'b mut Decoder<'a> {
+    type Error = KeyError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self)> {
+        let index = u32::from_be_bytes(self.array()?);
+        let variant = seed.deserialize(index.into_deserializer())?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'a, 'b> de::VariantAccess<'de> for &'b mut Decoder<'a> {
+    type Error = KeyError;
+
+    fn unit_variant(self) -> Result<()> {
+        Ok(())
+    }
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(self)
+    }
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(Fixed(self, len))
+    }
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_seq(Fixed(self, fields.len()))
+    }
+}
@@ -0,0 +1,250 @@
+mod de;
+mod ser;
+
+use crate::error::DBError;
+use crate::storage::is_reserved;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Keys are turned into bytes that sort the same way as the values they came from, and then into
+// lowercase hex, which sorts the same way as the bytes and is safe in any file name:
+//
+// * Integers are big endian, with the sign bit flipped for signed ones so negatives come first
+// * Floats are big endian too, with the bits of negatives flipped
+// * Strings and bytes end in 00 00, with every 00 in them written as 00 ff
+// * Options, sequences and maps put 00 in front of what's absent or ends them and 01 in front of
+//   every value
+// * Tuples and structs are just their fields one after another, enums the variant (u32) first
+//
+// Plain strings are used as they are, so that string keys stay readable and keep working with
+// everything saved before. Encoded keys start with a #, and so do plain strings starting with a #
+// get another one in front, so that no plain string can be mistaken for an encoded key. Plain
+// strings that look like the names sfsdb uses itself (__CACHE__) get a # in front too, so they
+// can't overwrite those.
+
+// What encoded keys start with
+const ENCODED: char = '#';
+// The newtype name of Encoded, for the encoder to tell it apart
+const ENCODED_NAME: &str = "__sfsdb_encoded_key__";
+
+/// Anything that can be used as a key, which is anything serializable.
+///
+/// Keys are encoded so that the file names sort in the same order as the keys themselves:
+/// integers by value (negatives first), strings and byte arrays byte by byte, tuples and structs
+/// field by field. Strings are used as they are, other keys are encoded into hex behind a `#` so
+/// they're safe to use as a file name. Strings starting with a `#`, or wrapped in double
+/// underscores like the names sfsdb uses itself, get a `#` in front.
+///
+/// ```no_run
+/// use sfsdb::GenericDatabase;
+///
+/// let mut db = sfsdb::new("events");
+/// db.save(&(42u32, 1_548_000_000u64), &String::from("login")).unwrap();
+/// db.save(&7u64, &String::from("logout")).unwrap();
+/// let event: String = db.load(&(42u32, 1_548_000_000u64)).unwrap();
+/// ```
+pub trait Key {
+    /// The key as it's saved.
+    fn encode_key(&self) -> Result<String, DBError>;
+}
+
+impl<K: Serialize + ?Sized> Key for K {
+    fn encode_key(&self) -> Result<String, DBError> {
+        encode(self)
+    }
+}
+
+/// A key exactly as it's saved, such as one listed by
+/// [`keys`](trait.GenericDatabase.html#method.keys), which is used as it is instead of being
+/// encoded again.
+///
+/// ```no_run
+/// use sfsdb::{Encoded, GenericDatabase};
+///
+/// let mut db = sfsdb::new("events");
+/// for key in db.keys() {
+///     let event: String = db.load(&Encoded(&key)).unwrap();
+/// }
+/// ```
+pub struct Encoded<'a>(pub &'a str);
+
+impl Serialize for Encoded<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct(ENCODED_NAME, self.0)
+    }
+}
+
+pub(crate) fn encode<K: Serialize + ?Sized>(key: &K) -> Result<String, DBError> {
+    let mut encoder = ser::Encoder::default();
+    key.serialize(&mut encoder)
+        .map_err(|e| DBError::key(&format!("Unable to encode key ({})", e)))?;
+    match encoder.raw {
+        Some(raw) if raw.is_empty() => Err(DBError::key("Empty key")),
+        Some(raw) if encoder.verbatim && is_reserved(&raw) => Err(DBError::key(&format!(
+            "{} is used by sfsdb itself, not a saved key",
+            raw
+        ))),
+        Some(raw) if encoder.verbatim => Ok(raw),
+        Some(raw) if raw.starts_with(ENCODED) || is_reserved(&raw) => {
+            Ok(format!("{}{}", ENCODED, raw))
+        }
+        Some(raw) => Ok(raw),
+        None if encoder.bytes.is_empty() => Err(DBError::key("Empty key")),
+        None => Ok(format!("{}{}", ENCODED, hex(&encoder.bytes))),
+    }
+}
+
+// Sorts the same way as value, like an encoded key, but only meant for comparing. Unlike keys,
+// values can be empty, and strings are escaped like within composite keys so that every value
+// sorts by its bytes alone.
+pub(crate) fn order<K: Serialize + ?Sized>(value: &K) -> Result<String, DBError> {
+    let mut encoder = ser::Encoder::nested();
+    value
+        .serialize(&mut encoder)
+        .map_err(|e| DBError::key(&format!("Unable to encode value ({})", e)))?;
+    Ok(hex(&encoder.bytes))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn decode<K>(encoded: &str) -> Result<K, DBError>
+where
+    for<'de> K: Deserialize<'de>,
+{
+    let mut decoder = de::Decoder::new(encoded);
+    let key = K::deserialize(&mut decoder)
+        .map_err(|e| DBError::key(&format!("Unable to decode {} ({})", encoded, e)))?;
+    decoder
+        .finish()
+        .map_err(|e| DBError::key(&format!("Unable to decode {} ({})", encoded, e)))?;
+    Ok(key)
+}
+
+#[derive(Debug)]
+pub struct KeyError(String);
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+impl serde::ser::Error for KeyError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        KeyError(msg.to_string())
+    }
+}
+
+impl serde::de::Error for KeyError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        KeyError(msg.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, Encoded};
+    use serde::{Deserialize, Serialize};
+    use std::fmt::Debug;
+
+    fn round_trip<K>(key: K)
+    where
+        K: Serialize + Debug + PartialEq,
+        for<'de> K: Deserialize<'de>,
+    {
+        let encoded = encode(&key).unwrap();
+        assert_eq!(decode::<K>(&encoded).unwrap(), key, "{}", encoded);
+    }
+
+    fn sorted<K: Serialize>(keys: &[K]) {
+        let encoded: Vec<String> = keys.iter().map(|k| encode(k).unwrap()).collect();
+        for pair in encoded.windows(2) {
+            assert!(pair[0] < pair[1], "{} >= {}", pair[0], pair[1]);
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Kind {
+        User(u32),
+        Group { name: String },
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(String::from("plain"));
+        round_trip(String::from("#hash"));
+        round_trip(String::from("##"));
+        round_trip(String::from("0000002a"));
+        round_trip(42u32);
+        round_trip(-7i64);
+        round_trip(1.5f64);
+        round_trip('x');
+        round_trip(true);
+        round_trip(Some(3u8));
+        round_trip((String::from("a\0b"), 1u16));
+        round_trip(vec![String::from("#"), String::new()]);
+        round_trip(Kind::User(9));
+        round_trip(Kind::Group {
+            name: String::from("admins"),
+        });
+    }
+
+    #[test]
+    fn strings_never_look_encoded() {
+        let int = encode(&42u32).unwrap();
+        let string = encode(int.as_str()).unwrap();
+        assert_ne!(int, string);
+        assert_eq!(decode::<String>(&string).unwrap(), int);
+        assert!(decode::<String>(&int).is_err());
+        assert!(decode::<u32>(&encode("0000002a").unwrap()).is_err());
+    }
+
+    #[test]
+    fn encoded_keys_stay_as_they_are() {
+        for key in &[encode(&42u32).unwrap(), encode("#hash").unwrap()] {
+            assert_eq!(&encode(&Encoded(key)).unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn reserved_names_are_escaped() {
+        for name in &["__CACHE__", "__INDEX__/../../escaped__"] {
+            let encoded = encode(*name).unwrap();
+            assert_eq!(encoded, format!("#{}", name));
+            assert_eq!(&decode::<String>(&encoded).unwrap(), name);
+        }
+        assert!(encode(&Encoded("__CACHE__")).is_err());
+    }
+
+    #[test]
+    fn empty_keys() {
+        assert!(encode("").is_err());
+        assert!(encode(&()).is_err());
+    }
+
+    #[test]
+    fn ordering_takes_empty_values() {
+        assert_eq!(super::order(&()).unwrap(), "");
+        assert_eq!(super::order("").unwrap(), "0000");
+        let values = ["", "#", "A", "__a__", "a"];
+        let ordered: Vec<String> = values.iter().map(|v| super::order(v).unwrap()).collect();
+        for pair in ordered.windows(2) {
+            assert!(pair[0] < pair[1], "{} >= {}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn order() {
+        sorted(&[i32::MIN, -1, 0, 1, 255, 256, i32::MAX]);
+        sorted(&[0u64, 1, 1 << 32, u64::MAX]);
+        sorted(&[f64::NEG_INFINITY, -2.5, -0.0, 0.0, 1e-9, 3.0, f64::INFINITY]);
+        sorted(&["!", "#", "##", "#a", "a", "ab", "b"]);
+        sorted(&[("a", 2u8), ("a", 3), ("a\0", 0), ("ab", 0), ("b", 0)]);
+        sorted(&[None, Some(0u8), Some(1)]);
+        sorted(&[vec![], vec![1u8], vec![1, 0], vec![2]]);
+    }
+}
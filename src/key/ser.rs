@@ -0,0 +1,273 @@
+use super::KeyError;
+use serde::ser::{self, Serialize};
+
+#[derive(Default)]
+pub struct Encoder {
+    pub bytes: Vec<u8>,
+    // Set when the whole key is a string, which is then used as it is
+    pub raw: Option<String>,
+    // Set when the whole key is an Encoded one, whose raw string is used without escaping
+    pub verbatim: bool,
+    // Whether anything but newtypes was serialized yet
+    nested: bool,
+}
+
+impl Encoder {
+    // Encodes values as if they were part of a composite key, strings included
+    pub fn nested() -> Self {
+        Encoder {
+            nested: true,
+            ..Encoder::default()
+        }
+    }
+
+    fn nest(&mut self) {
+        self.nested = true;
+    }
+
+    fn escaped(&mut self, bytes: &[u8]) {
+        self.nest();
+        for b in bytes {
+            self.bytes.push(*b);
+            if *b == 0 {
+                self.bytes.push(0xff);
+            }
+        }
+        self.bytes.extend_from_slice(&[0, 0]);
+    }
+}
+
+type Result = std::result::Result<(), KeyError>;
+
+impl ser::Serializer for &mut Encoder {
+    type Ok = ();
+    type Error = KeyError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result {
+        self.nest();
+        self.bytes.push(v as u8);
+        Ok(())
+    }
+    fn serialize_i8(self, v: i8) -> Result {
+        self.serialize_u8((v as u8) ^ 0x80)
+    }
+    fn serialize_i16(self, v: i16) -> Result {
+        self.serialize_u16((v as u16) ^ 0x8000)
+    }
+    fn serialize_i32(self, v: i32) -> Result {
+        self.serialize_u32((v as u32) ^ 0x8000_0000)
+    }
+    fn serialize_i64(self, v: i64) -> Result {
+        self.serialize_u64((v as u64) ^ 0x8000_0000_0000_0000)
+    }
+    fn serialize_i128(self, v: i128) -> Result {
+        self.serialize_u128((v as u128) ^ (1 << 127))
+    }
+    fn serialize_u8(self, v: u8) -> Result {
+        self.nest();
+        self.bytes.push(v);
+        Ok(())
+    }
+    fn serialize_u16(self, v: u16) -> Result {
+        self.nest();
+        self.bytes.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+    fn serialize_u32(self, v: u32) -> Result {
+        self.nest();
+        self.bytes.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+    fn serialize_u64(self, v: u64) -> Result {
+        self.nest();
+        self.bytes.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+    fn serialize_u128(self, v: u128) -> Result {
+        self.nest();
+        self.bytes.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+    fn serialize_f32(self, v: f32) -> Result {
+        let bits = v.to_bits();
+        self.serialize_u32(if bits >> 31 == 1 {
+            !bits
+        } else {
+            bits ^ (1 << 31)
+        })
+    }
+    fn serialize_f64(self, v: f64) -> Result {
+        let bits = v.to_bits();
+        self.serialize_u64(if bits >> 63 == 1 {
+            !bits
+        } else {
+            bits ^ (1 << 63)
+        })
+    }
+    fn serialize_char(self, v: char) -> Result {
+        self.serialize_u32(v as u32)
+    }
+    fn serialize_str(self, v: &str) -> Result {
+        if !self.nested {
+            self.nest();
+            self.raw = Some(v.to_owned());
+            return Ok(());
+        }
+        self.escaped(v.as_bytes());
+        Ok(())
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result {
+        self.escaped(v);
+        Ok(())
+    }
+    fn serialize_none(self) -> Result {
+        self.serialize_u8(0)
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result {
+        self.serialize_u8(1)?;
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result {
+        self.nest();
+        Ok(())
+    }
+    fn serialize_unit_struct(self, _: &'static str) -> Result {
+        self.serialize_unit()
+    }
+    fn serialize_unit_variant(self, _: &'static str, index: u32, _: &'static str) -> Result {
+        self.serialize_u32(index)
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result {
+        if name == super::ENCODED_NAME && !self.nested {
+            self.verbatim = true;
+        }
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+        value: &T,
+    ) -> Result {
+        self.serialize_u32(index)?;
+        value.serialize(self)
+    }
+    fn serialize_seq(self, _: Option<usize>) -> std::result::Result<Self, KeyError> {
+        self.nest();
+        Ok(self)
+    }
+    fn serialize_tuple(self, _: usize) -> std::result::Result<Self, KeyError> {
+        self.nest();
+        Ok(self)
+    }
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> std::result::Result<Self, KeyError> {
+        self.nest();
+        Ok(self)
+    }
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+        _: usize,
+    ) -> std::result::Result<Self, KeyError> {
+        self.serialize_u32(index)?;
+        Ok(self)
+    }
+    fn serialize_map(self, _: Option<usize>) -> std::result::Result<Self, KeyError> {
+        self.nest();
+        Ok(self)
+    }
+    fn serialize_struct(self, _: &'static str, _: usize) -> std::result::Result<Self, KeyError> {
+        self.nest();
+        Ok(self)
+    }
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+        _: usize,
+    ) -> std::result::Result<Self, KeyError> {
+        self.serialize_u32(index)?;
+        Ok(self)
+    }
+    // The decoder isn't either, so types such as addresses must pick the same form for both
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+impl ser::SerializeSeq for &mut Encoder {
+    type Ok = ();
+    type Error = KeyError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result {
+        self.bytes.push(1);
+        value.serialize(&mut **self)
+    }
+    fn end(self) -> Result {
+        self.bytes.push(0);
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut Encoder {
+    type Ok = ();
+    type Error = KeyError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result {
+        self.bytes.push(1);
+        key.serialize(&mut **self)
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result {
+        value.serialize(&mut **self)
+    }
+    fn end(self) -> Result {
+        self.bytes.push(0);
+        Ok(())
+    }
+}
+
+// Tuples, structs and their variants have a fixed amount of fields, so they need no markers
+macro_rules! fixed {
+    ($trait:ident, $method:ident $(, $name:ident)*) => {
+        impl<'a> ser::$trait for &'a mut Encoder {
+            type Ok = ();
+            type Error = KeyError;
+
+            fn $method<T: Serialize + ?Sized>(
+                &mut self,
+                $($name: &'static str,)*
+                value: &T,
+            ) -> Result {
+                value.serialize(&mut **self)
+            }
+            fn end(self) -> Result {
+                Ok(())
+            }
+        }
+    };
+}
+
+fixed!(SerializeTuple, serialize_element);
+fixed!(SerializeTupleStruct, serialize_field);
+fixed!(SerializeTupleVariant, serialize_field);
+fixed!(SerializeStruct, serialize_field, _name);
+fixed!(SerializeStructVariant, serialize_field, _name);
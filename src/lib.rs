@@ -3,27 +3,42 @@ use serde::{Deserialize, Serialize};
 pub(crate) mod cache;
 pub mod database;
 mod error;
-mod filesystem;
+mod key;
+mod presence;
+mod sequence;
+pub mod storage;
 
-use crate::error::DBError;
+pub use crate::cache::pool::CachePool;
+pub use crate::error::DBError;
+pub use crate::key::{Encoded, Key};
+pub use crate::sequence::ulid;
 use database::{cached::CachedDB, indexed::IndexedDB, simple::SimpleDB};
 
 /// All databases implement this trait.
+///
+/// Keys can be anything that implements [`Key`](trait.Key.html), such as strings, integers and
+/// tuples of them.
 pub trait GenericDatabase {
     /// Get the filepath of the database.
     fn location(&self) -> &str;
     /// Check if a key exists in the database.
-    fn exists(&self, key: &str) -> bool;
+    fn exists(&self, key: &(impl Key + ?Sized)) -> bool;
     /// Save a value of type T to the database.
-    fn save<T>(&mut self, key: &str, data: &T) -> Result<(), DBError>
+    fn save<T>(&mut self, key: &(impl Key + ?Sized), data: &T) -> Result<(), DBError>
     where
         for<'de> T: Deserialize<'de> + Serialize + Clone;
     /// Load a value of type T from the database.
-    fn load<T>(&mut self, identifier: &str) -> Result<T, DBError>
+    fn load<T>(&mut self, identifier: &(impl Key + ?Sized)) -> Result<T, DBError>
     where
         for<'de> T: Deserialize<'de> + Serialize + Clone;
     /// Remove a key/value from the database.
-    fn delete(&mut self, identifier: &str);
+    fn delete(&mut self, identifier: &(impl Key + ?Sized));
+    /// All keys in the database as they're saved, sorted. Wrap them in
+    /// [`Encoded`](struct.Encoded.html) to pass them back in. Databases that can't list their
+    /// keys have none by default.
+    fn keys(&self) -> Vec<String> {
+        Vec::new()
+    }
 }
 
 fn init(dir: &str) {
@@ -52,3 +67,19 @@ where
     init(location);
     IndexedDB::new(location, cache, resync_every)
 }
+
+/// Like [`new_indexed`](fn.new_indexed.html), but the index is left on disk with only up to
+/// `hot_indexes` of it in memory, for indexes that don't fit in memory. See
+/// [`IndexedDB::from_storage_on_disk`](database/indexed/struct.IndexedDB.html#method.from_storage_on_disk).
+pub fn new_indexed_on_disk<I>(
+    location: &str,
+    cache: Option<usize>,
+    resync_every: u16,
+    hot_indexes: Option<usize>,
+) -> IndexedDB<I>
+where
+    for<'de> I: Deserialize<'de> + Serialize + Clone,
+{
+    init(location);
+    IndexedDB::new_on_disk(location, cache, resync_every, hot_indexes)
+}
@@ -0,0 +1,79 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+// Roughly a 1% false positive rate while the amount of keys stays below the expected amount
+const BITS_PER_KEY: usize = 10;
+const HASHES: u64 = 7;
+
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    expected: usize,
+    inserted: usize,
+    deleted: usize,
+}
+
+impl BloomFilter {
+    pub fn new(expected: usize) -> Self {
+        let expected = expected.max(1);
+        BloomFilter {
+            bits: vec![0; (expected * BITS_PER_KEY + 63) / 64],
+            expected,
+            inserted: 0,
+            deleted: 0,
+        }
+    }
+
+    // Build a filter with room for both the expected amount of keys and the ones given
+    pub fn build<'a, I>(expected: usize, keys: I) -> Self
+    where
+        I: Iterator<Item = &'a str> + Clone,
+    {
+        let mut filter = BloomFilter::new(expected.max(keys.clone().count() * 2));
+        for key in keys {
+            filter.insert(key);
+        }
+        filter
+    }
+
+    // Two independent hashes combined into as many as needed (Kirsch-Mitzenmacher)
+    fn positions(&self, key: &str) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let h = hasher.finish();
+        let (h1, h2) = (h & 0xffff_ffff, (h >> 32) | 1);
+        let len = self.bits.len() as u64 * 64;
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    // Only keys that set a new bit are counted, since the rest were most likely inserted already
+    pub fn insert(&mut self, key: &str) {
+        let mut new = false;
+        for p in self.positions(key).collect::<Vec<usize>>() {
+            new |= self.bits[p / 64] & (1 << (p % 64)) == 0;
+            self.bits[p / 64] |= 1 << (p % 64);
+        }
+        if new {
+            self.inserted += 1;
+        }
+    }
+
+    // False means the key definitely doesn't exist, true that it might
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.positions(key)
+            .all(|p| self.bits[p / 64] & (1 << (p % 64)) != 0)
+    }
+
+    // Bits can't be unset, so deletes are only counted to know when to rebuild
+    pub fn remove(&mut self) {
+        self.deleted += 1;
+    }
+
+    // Either too many deleted keys are still matching, or there's more keys than it was sized for
+    pub fn should_rebuild(&self) -> bool {
+        self.deleted * 2 > self.inserted || self.inserted > self.expected
+    }
+
+    pub fn expected(&self) -> usize {
+        self.expected
+    }
+}
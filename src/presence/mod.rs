@@ -0,0 +1,80 @@
+mod bloom;
+mod negative;
+
+use bloom::BloomFilter;
+use negative::NegativeCache;
+
+// Answers "this key doesn't exist" without touching the file system, when possible. Both the bloom
+// filter and the negative cache are optional and off by default.
+#[derive(Default)]
+pub struct Presence {
+    bloom: Option<BloomFilter>,
+    misses: Option<NegativeCache>,
+}
+
+impl Presence {
+    pub fn enable_bloom<'a, I>(&mut self, expected: usize, keys: I)
+    where
+        I: Iterator<Item = &'a str> + Clone,
+    {
+        self.bloom = Some(BloomFilter::build(expected, keys));
+    }
+
+    pub fn enable_negative_cache(&mut self, capacity: usize) {
+        self.misses = Some(NegativeCache::new(capacity));
+    }
+
+    // True if the key is known to not exist
+    pub fn absent(&self, key: &str) -> bool {
+        if let Some(bloom) = &self.bloom {
+            if !bloom.may_contain(key) {
+                return true;
+            }
+        }
+        match &self.misses {
+            Some(misses) => misses.contains(key),
+            None => false,
+        }
+    }
+
+    pub fn saved(&mut self, key: &str) {
+        if let Some(bloom) = self.bloom.as_mut() {
+            bloom.insert(key);
+        }
+        if let Some(misses) = self.misses.as_mut() {
+            misses.remove(key);
+        }
+    }
+
+    pub fn deleted(&mut self, key: &str) {
+        if let Some(bloom) = self.bloom.as_mut() {
+            bloom.remove();
+        }
+        self.missed(key);
+    }
+
+    pub fn missed(&mut self, key: &str) {
+        if let Some(misses) = self.misses.as_mut() {
+            misses.insert(key);
+        }
+    }
+
+    // The bloom filter can't forget keys, so it has to be rebuilt every now and then to not match
+    // on everything that's ever been deleted.
+    pub fn should_rebuild(&self) -> bool {
+        match &self.bloom {
+            Some(bloom) => bloom.should_rebuild(),
+            None => false,
+        }
+    }
+
+    pub fn rebuild<'a, I>(&mut self, keys: I)
+    where
+        I: Iterator<Item = &'a str> + Clone,
+    {
+        if let Some(bloom) = &self.bloom {
+            let expected = bloom.expected();
+            self.enable_bloom(expected, keys);
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use hashbrown::HashMap;
+use std::collections::VecDeque;
+
+// A bounded set of keys known to not exist. The oldest misses are forgotten first.
+pub struct NegativeCache {
+    // Every key along with when it was inserted
+    keys: HashMap<String, u64>,
+    // Keys from oldest to newest. Removed keys stay behind until they're skipped, which is when
+    // their insertion doesn't match the one in keys anymore.
+    order: VecDeque<(u64, String)>,
+    capacity: usize,
+    next: u64,
+}
+
+impl NegativeCache {
+    pub fn new(capacity: usize) -> Self {
+        NegativeCache {
+            keys: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+            next: 0,
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.keys.contains_key(key)
+    }
+
+    fn is_live(&self, inserted: u64, key: &str) -> bool {
+        self.keys.get(key) == Some(&inserted)
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        if self.capacity == 0 || self.keys.contains_key(key) {
+            return;
+        }
+        if self.keys.len() >= self.capacity {
+            while let Some((inserted, oldest)) = self.order.pop_front() {
+                if self.is_live(inserted, &oldest) {
+                    self.keys.remove(&oldest);
+                    break;
+                }
+            }
+        }
+        // Skipping removed keys only happens on eviction, so they're cleared out once they
+        // outnumber the live ones
+        if self.order.len() >= self.capacity * 2 {
+            let keys = &self.keys;
+            self.order
+                .retain(|(inserted, key)| keys.get(key) == Some(inserted));
+        }
+        self.keys.insert(key.to_owned(), self.next);
+        self.order.push_back((self.next, key.to_owned()));
+        self.next += 1;
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.keys.remove(key);
+    }
+}
@@ -0,0 +1,44 @@
+mod ulid;
+
+pub use ulid::ulid;
+
+use crate::error::DBError;
+use crate::storage::{self, Storage};
+use rmp_serde::encode;
+
+// The sequence used by insert
+pub const INSERT_SEQUENCE: &str = "id";
+
+// Every sequence is its own reserved key, so it's never listed or cached
+fn sequence_key(name: &str) -> Result<String, DBError> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(DBError::save(&format!(
+            "{:?} can't be used as a sequence name",
+            name
+        )));
+    }
+    Ok(format!("__SEQUENCE_{}__", name))
+}
+
+// The next number of a sequence, starting at 1. It's durably written before it's handed out, so
+// a number is never handed out twice, even across crashes.
+pub fn next_id<S: Storage>(storage: &mut S, name: &str) -> Result<u64, DBError> {
+    let key = sequence_key(name)?;
+    let last = if storage.contains(&key) {
+        storage::load::<u64, S>(storage, &key)?
+    } else {
+        0
+    };
+    let next = last
+        .checked_add(1)
+        .ok_or_else(|| DBError::save(&format!("Sequence {} is exhausted", name)))?;
+    match encode::to_vec(&next) {
+        Ok(bytes) => storage.put_durable(&key, &bytes)?,
+        Err(_) => return Err(DBError::save(&format!("Unable to serialize {}", key))),
+    }
+    Ok(next)
+}
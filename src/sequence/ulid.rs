@@ -0,0 +1,57 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CROCKFORD: &[u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const RANDOM_BITS: u32 = 80;
+const RANDOM_MAX: u128 = (1 << RANDOM_BITS) - 1;
+
+// The last ULID handed out, as milliseconds and random part
+static LAST: Mutex<(u64, u128)> = Mutex::new((0, 0));
+
+/// A new [ULID](https://github.com/ulid/spec), which is a unique id that sorts by the time it was
+/// created. ULIDs created in the same millisecond (or while the clock goes backwards) still sort
+/// in the order they were created, within one process.
+///
+/// ```
+/// let a = sfsdb::ulid();
+/// let b = sfsdb::ulid();
+/// assert!(a < b);
+/// ```
+pub fn ulid() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+        & ((1 << 48) - 1);
+
+    let mut last = LAST.lock().unwrap_or_else(|e| e.into_inner());
+    let (time, random) = if now > last.0 {
+        (now, random())
+    } else if last.1 < RANDOM_MAX {
+        (last.0, last.1 + 1)
+    } else {
+        // Ran out of room within the millisecond, so borrow the next one
+        (last.0 + 1, random())
+    };
+    *last = (time, random);
+
+    let value = (u128::from(time) << RANDOM_BITS) | random;
+    (0..26)
+        .rev()
+        .map(|i| CROCKFORD[((value >> (i * 5)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+// 80 random bits. Every RandomState is seeded differently, which is random enough for ids and
+// saves a dependency. The top bit is left clear so that ids created right after keep room to
+// count up in.
+fn random() -> u128 {
+    let mut high = RandomState::new().build_hasher();
+    high.write_u8(0);
+    let mut low = RandomState::new().build_hasher();
+    low.write_u8(1);
+    let bits = (u128::from(high.finish()) << 64) | u128::from(low.finish());
+    bits & (RANDOM_MAX >> 1)
+}
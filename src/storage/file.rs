@@ -0,0 +1,632 @@
+use super::{children, delete_listed_tree, is_reserved, tree_root, Storage};
+use crate::error::DBError;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Only exists in sharded and hierarchical databases
+const LAYOUT_FILE: &str = "__LAYOUT__";
+const SHARDED: &[u8] = b"sharded";
+// A migration to the sharded layout that hasn't finished yet
+const SHARDING: &[u8] = b"sharding";
+// Where the keys wait during a migration
+const STAGING: &str = "__SHARDING__";
+const HIERARCHICAL: &[u8] = b"hierarchical";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Layout {
+    Flat,
+    Sharded,
+    Hierarchical,
+}
+
+/// The default storage, which saves every key as its own file in a directory.
+///
+/// Directories with millions of files get slow, so keys can also be spread over nested
+/// subdirectories named after a hash of the key (`ab/cd/<key>`), see
+/// [`new_sharded`](#method.new_sharded). Or the `/` in keys can be mapped to real subdirectories,
+/// see [`new_hierarchical`](#method.new_hierarchical). A database remembers its layout, so it can
+/// be reopened with [`new`](#method.new) either way.
+pub struct FileStorage {
+    location: String,
+    layout: Layout,
+}
+
+impl FileStorage {
+    /// Use the directory at location, creating it if needed.
+    pub fn new(location: &str) -> Self {
+        if !Path::new(location).exists() && fs::create_dir_all(location).is_err() {
+            panic!("sfsdb: Could not create database at {}", location)
+        }
+        let mut storage = FileStorage {
+            location: String::from(location),
+            layout: Layout::Flat,
+        };
+        let mut marker = PathBuf::from(location);
+        marker.push(LAYOUT_FILE);
+        match fs::read(marker) {
+            Ok(ref layout) if layout == SHARDED => storage.layout = Layout::Sharded,
+            Ok(ref layout) if layout == SHARDING => {
+                // Finishes migrations that were interrupted
+                storage
+                    .migrate_to_sharded()
+                    .map_err(|e| eprintln!("{}", e))
+                    .ok();
+            }
+            Ok(ref layout) if layout == HIERARCHICAL => storage.layout = Layout::Hierarchical,
+            _ => (),
+        }
+        storage
+    }
+
+    /// Use the directory at location with hierarchical keys, creating it if needed. Every `/` in
+    /// a key is a subdirectory, so `tenants/acme/users/42` is saved as the file `42` in
+    /// `tenants/acme/users`. A key can't be both a value and have keys below it, saving
+    /// `tenants/acme` fails while `tenants/acme/users/42` exists and the other way around.
+    ///
+    /// An existing flat database can be opened this way (its keys never contain a `/`), but
+    /// hierarchical keys can't be sharded.
+    pub fn new_hierarchical(location: &str) -> Self {
+        let mut storage = FileStorage::new(location);
+        match storage.layout {
+            Layout::Hierarchical => (),
+            Layout::Sharded => panic!(
+                "sfsdb: {} is sharded and can't use hierarchical keys",
+                location
+            ),
+            Layout::Flat => {
+                let mut marker = PathBuf::from(location);
+                marker.push(LAYOUT_FILE);
+                if let Err(e) = fs::write(marker, HIERARCHICAL) {
+                    panic!("sfsdb: Could not set up database at {}: {}", location, e)
+                }
+                storage.layout = Layout::Hierarchical;
+            }
+        }
+        storage
+    }
+
+    /// Use the directory at location with the sharded layout, creating it if needed. An existing
+    /// database with every key directly in the directory is migrated.
+    pub fn new_sharded(location: &str) -> Self {
+        let mut storage = FileStorage::new(location);
+        if let Err(e) = storage.migrate_to_sharded() {
+            panic!("sfsdb: Could not shard database at {}: {}", location, e)
+        }
+        storage
+    }
+
+    /// Move every key (including those in namespaces, such as the index) into the sharded
+    /// layout, in place. If interrupted, the migration is finished the next time the database is
+    /// opened. Nothing is moved if a collection has the name of a shard (two hex digits, such as
+    /// `ab`), since it couldn't be told apart from one afterwards.
+    pub fn migrate_to_sharded(&mut self) -> Result<(), DBError> {
+        if self.layout == Layout::Hierarchical {
+            return Err(DBError::save(&format!(
+                "{} uses hierarchical keys, which can't be sharded",
+                self.location
+            )));
+        }
+        self.try_migrate()
+            .map_err(|e| DBError::save(&format!("Could not shard {} ({})", self.location, e)))
+    }
+
+    fn try_migrate(&mut self) -> io::Result<()> {
+        let mut marker = PathBuf::from(&self.location);
+        marker.push(LAYOUT_FILE);
+        match fs::read(&marker) {
+            Ok(ref layout) if layout == SHARDED => {
+                self.layout = Layout::Sharded;
+                return Ok(());
+            }
+            // Directories named like shards are shards by now
+            Ok(ref layout) if layout == SHARDING => (),
+            _ => check_shardable(Path::new(&self.location))?,
+        }
+        // Marking it first means an interrupted migration is picked up again on open, and only
+        // then is the whole directory gone through again
+        fs::write(&marker, SHARDING)?;
+
+        // Every key is moved out of the way first, since a key can have the name of a shard
+        let mut staging = PathBuf::from(&self.location);
+        staging.push(STAGING);
+        fs::create_dir_all(&staging)?;
+        for name in list_files(Path::new(&self.location)) {
+            fs::rename(Path::new(&self.location).join(&name), staging.join(&name))?;
+        }
+        for name in list_files(&staging) {
+            let to = self.shard_path(&name);
+            fs::create_dir_all(to.parent().unwrap())?;
+            fs::rename(staging.join(&name), to)?;
+        }
+        fs::remove_dir(&staging)?;
+
+        for entry in fs::read_dir(&self.location)? {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if entry.file_type()?.is_dir() && !is_shard(&name) {
+                FileStorage::new(&entry.path().to_string_lossy()).try_migrate()?;
+            }
+        }
+        fs::write(marker, SHARDED)?;
+        self.layout = Layout::Sharded;
+        Ok(())
+    }
+
+    fn shard_path(&self, key: &str) -> PathBuf {
+        let hash = format!("{:016x}", shard_hash(key.as_bytes()));
+        let mut path = PathBuf::from(&self.location);
+        path.push(&hash[0..2]);
+        path.push(&hash[2..4]);
+        path.push(key);
+        path
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        if self.layout == Layout::Sharded && !is_reserved(key) {
+            return self.shard_path(key);
+        }
+        let mut path = PathBuf::new();
+        path.push(&self.location);
+        if self.layout == Layout::Hierarchical && !is_reserved(key) {
+            path.extend(key.split('/'));
+        } else {
+            path.push(key);
+        }
+        path
+    }
+
+    // Keys that would escape the directory are refused, and so are those that would hide among
+    // the files of sfsdb in the hierarchical layout. The names of sfsdb itself are always a
+    // single file directly in the directory.
+    fn check(&self, key: &str) -> Result<(), String> {
+        if key.is_empty() || key == "." || key == ".." || key.contains('\0') {
+            return Err(format!("{:?} can't be used as a file name", key));
+        }
+        if self.layout != Layout::Hierarchical || is_reserved(key) {
+            if key.contains('/') {
+                return Err(format!("{:?} can't be used as a file name", key));
+            }
+            return Ok(());
+        }
+        match key
+            .split('/')
+            .find(|p| p.is_empty() || *p == "." || *p == ".." || is_reserved(p))
+        {
+            Some(part) => Err(format!("{:?} can't be part of the key {}", part, key)),
+            None => Ok(()),
+        }
+    }
+
+    // A key that's saved as a file can't also be a directory of other keys
+    fn check_conflicts(&self, key: &str, path: &Path) -> Result<(), String> {
+        if path.is_dir() {
+            return Err(format!("{} has keys below it, so it can't be a value", key));
+        }
+        let mut parent = path.parent();
+        while let Some(dir) = parent {
+            if dir == Path::new(&self.location) {
+                break;
+            }
+            if dir.is_file() {
+                return Err(format!(
+                    "{} is a value, so {} can't be below it",
+                    dir.strip_prefix(&self.location).unwrap_or(dir).display(),
+                    key
+                ));
+            }
+            if is_namespace(dir) {
+                return Err(format!(
+                    "{} is a collection, so {} can't be below it",
+                    dir.strip_prefix(&self.location).unwrap_or(dir).display(),
+                    key
+                ));
+            }
+            parent = dir.parent();
+        }
+        Ok(())
+    }
+
+    // Directories that were only there for keys that are now deleted
+    fn remove_empty_parents(&self, path: &Path) {
+        let mut parent = path.parent();
+        while let Some(dir) = parent {
+            if dir == Path::new(&self.location) || fs::remove_dir(dir).is_err() {
+                break;
+            }
+            parent = dir.parent();
+        }
+    }
+}
+
+impl Storage for FileStorage {
+    fn location(&self) -> &str {
+        &self.location
+    }
+    fn is_removed(&self) -> bool {
+        !Path::new(&self.location).is_dir()
+    }
+    fn get(&self, key: &str) -> Result<Vec<u8>, DBError> {
+        self.check(key).map_err(|e| DBError::load(&e))?;
+        fs::read(self.path(key)).map_err(|e| DBError::load(&format!("{}", e)))
+    }
+    fn get_many(&self, keys: &[&str]) -> Vec<Result<Vec<u8>, DBError>> {
+        // Read in path order, which keeps the files of a directory (or shard) together, and on
+        // every core at once with the parallel feature
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        let paths: Vec<PathBuf> = keys.iter().map(|key| self.path(key)).collect();
+        order.sort_unstable_by(|a, b| paths[*a].cmp(&paths[*b]));
+        let read = |i: &usize| match self.check(keys[*i]) {
+            Ok(()) => fs::read(&paths[*i]).map_err(|e| DBError::load(&format!("{}", e))),
+            Err(e) => Err(DBError::load(&e)),
+        };
+        #[cfg(feature = "parallel")]
+        let read: Vec<_> = {
+            use rayon::prelude::*;
+            order.par_iter().map(read).collect()
+        };
+        #[cfg(not(feature = "parallel"))]
+        let read: Vec<_> = order.iter().map(read).collect();
+
+        let mut values: Vec<Option<Result<Vec<u8>, DBError>>> = keys.iter().map(|_| None).collect();
+        for (i, value) in order.into_iter().zip(read) {
+            values[i] = Some(value);
+        }
+        values.into_iter().flatten().collect()
+    }
+    fn put(&mut self, key: &str, data: &[u8]) -> Result<(), DBError> {
+        let path = self.path(key);
+        self.check(key).map_err(|e| DBError::save(&e))?;
+        if self.layout == Layout::Hierarchical {
+            self.check_conflicts(key, &path)
+                .map_err(|e| DBError::save(&e))?;
+        }
+        let written = match fs::write(&path, data) {
+            // The first key in a shard or subdirectory creates its directory
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && self.layout != Layout::Flat => {
+                fs::create_dir_all(path.parent().unwrap()).and_then(|_| fs::write(&path, data))
+            }
+            r => r,
+        };
+        written.map_err(|e| DBError::save(&format!("Could not write {} ({})", path.display(), e)))
+    }
+    fn append(&mut self, key: &str, data: &[u8]) -> Result<(), DBError> {
+        let path = self.path(key);
+        self.check(key).map_err(|e| DBError::save(&e))?;
+        if self.layout == Layout::Hierarchical {
+            self.check_conflicts(key, &path)
+                .map_err(|e| DBError::save(&e))?;
+        }
+        let open = || fs::OpenOptions::new().create(true).append(true).open(&path);
+        let file = match open() {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && self.layout != Layout::Flat => {
+                fs::create_dir_all(path.parent().unwrap()).and_then(|_| open())
+            }
+            r => r,
+        };
+        file.and_then(|mut file| file.write_all(data))
+            .map_err(|e| DBError::save(&format!("Could not write {} ({})", path.display(), e)))
+    }
+    fn put_durable(&mut self, key: &str, data: &[u8]) -> Result<(), DBError> {
+        let path = self.path(key);
+        self.check(key).map_err(|e| DBError::save(&e))?;
+        if self.layout == Layout::Hierarchical {
+            self.check_conflicts(key, &path)
+                .map_err(|e| DBError::save(&e))?;
+        }
+        // Written next to it and then renamed over it, since renames are atomic. The name is
+        // reserved so a leftover of a crash is never listed.
+        let temp = path.with_file_name(format!("__TEMP_{}__", key.replace('/', "_")));
+        let written = fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| fs::File::create(&temp))
+            .and_then(|mut file| file.write_all(data).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&temp, &path))
+            .map(|_| {
+                // The rename itself is only durable once the directory is synced, which isn't
+                // possible everywhere
+                fs::File::open(path.parent().unwrap())
+                    .and_then(|dir| dir.sync_all())
+                    .ok();
+            });
+        written.map_err(|e| DBError::save(&format!("Could not write {} ({})", path.display(), e)))
+    }
+    fn delete(&mut self, key: &str) -> Result<(), DBError> {
+        self.check(key).map_err(|e| DBError::delete(&e))?;
+        let path = self.path(key);
+        fs::remove_file(&path)
+            .map_err(|_| DBError::delete(&format!("Could not delete {}", path.display())))?;
+        if self.layout == Layout::Hierarchical {
+            self.remove_empty_parents(&path);
+        }
+        Ok(())
+    }
+    fn contains(&self, key: &str) -> bool {
+        self.check(key).is_ok() && self.path(key).is_file()
+    }
+    fn list(&self) -> Vec<String> {
+        if self.layout == Layout::Hierarchical {
+            let mut keys = Vec::new();
+            list_tree(Path::new(&self.location), "", &mut keys);
+            return keys;
+        }
+        if self.layout == Layout::Flat {
+            return list_files(Path::new(&self.location));
+        }
+        let mut keys = Vec::new();
+        for outer in list_dirs(Path::new(&self.location)) {
+            for inner in list_dirs(&outer) {
+                keys.extend(list_files(&inner));
+            }
+        }
+        keys
+    }
+    fn namespace(&self, name: &str) -> Result<Self, DBError> {
+        let mut path = PathBuf::new();
+        path.push(&self.location);
+        path.push(name);
+        if self.layout == Layout::Hierarchical {
+            return Ok(FileStorage::new_hierarchical(&path.to_string_lossy()));
+        }
+        if self.layout != Layout::Sharded {
+            return Ok(FileStorage::new(&path.to_string_lossy()));
+        }
+        if is_shard(name) {
+            return Err(DBError::key(&format!(
+                "{} can't be used as a namespace of a sharded database",
+                name
+            )));
+        }
+        let mut storage = FileStorage::new(&path.to_string_lossy());
+        storage.migrate_to_sharded()?;
+        Ok(storage)
+    }
+    fn list_children(&self, prefix: &str) -> Vec<String> {
+        let prefix = prefix.trim_end_matches('/');
+        if self.layout != Layout::Hierarchical
+            || (!prefix.is_empty() && self.check(prefix).is_err())
+        {
+            return children(&self.list(), prefix);
+        }
+        let entries = match fs::read_dir(self.path(prefix)) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mut children: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| !is_namespace(&entry.path()))
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|name| !is_reserved(name))
+            .collect();
+        children.sort();
+        children
+    }
+    fn delete_tree(&mut self, prefix: &str) -> Result<(), DBError> {
+        if self.layout != Layout::Hierarchical {
+            return delete_listed_tree(self, prefix);
+        }
+        let root = tree_root(prefix)?;
+        self.check(root).map_err(|e| DBError::delete(&e))?;
+        let path = self.path(root);
+        let removed = if is_namespace(&path) {
+            return Err(DBError::delete(&format!(
+                "{} is a collection, not keys",
+                root
+            )));
+        } else if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else if path.is_file() {
+            fs::remove_file(&path)
+        } else {
+            return Ok(());
+        };
+        removed.map_err(|e| {
+            DBError::delete(&format!("Could not delete {} ({})", path.display(), e))
+        })?;
+        self.remove_empty_parents(&path);
+        Ok(())
+    }
+}
+
+// Namespaces of hierarchical storage remember their layout, while plain subdirectories of keys
+// don't
+fn is_namespace(dir: &Path) -> bool {
+    dir.join(LAYOUT_FILE).is_file()
+}
+
+// Collections (or anything else) in a directory that isn't sharded yet can't have the name of a
+// shard, or they'd be taken for one once it is
+fn check_shardable(dir: &Path) -> io::Result<()> {
+    if fs::read(dir.join(LAYOUT_FILE))
+        .map(|layout| layout == SHARDED || layout == SHARDING)
+        .unwrap_or(false)
+    {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        match entry.file_name().to_str() {
+            Some(name) if is_shard(name) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "the collection {} has the name of a shard",
+                        entry.path().display()
+                    ),
+                ))
+            }
+            _ => check_shardable(&entry.path())?,
+        }
+    }
+    Ok(())
+}
+
+// Shard directories are named after two hex digits of the hash
+fn is_shard(name: &str) -> bool {
+    name.len() == 2
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+// FNV-1a, which is stable across platforms and versions unlike the hasher of the standard
+// library. Finished off with the MurmurHash3 mixer, since FNV barely changes the high bits of
+// short keys and those decide the shard.
+fn shard_hash(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash: u64, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash
+}
+
+fn list_files(dir: &Path) -> Vec<String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map(|t| t.is_file()).unwrap_or(false))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| !is_reserved(name))
+        .collect()
+}
+
+// Every file below dir as a key, skipping namespaces and the files of sfsdb
+fn list_tree(dir: &Path, prefix: &str, keys: &mut Vec<String>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if is_reserved(&name) {
+            continue;
+        }
+        let key = format!("{}{}", prefix, name);
+        match entry.file_type() {
+            Ok(t) if t.is_dir() && !is_namespace(&entry.path()) => {
+                list_tree(&entry.path(), &format!("{}/", key), keys)
+            }
+            Ok(t) if t.is_file() => keys.push(key),
+            _ => (),
+        }
+    }
+}
+
+fn list_dirs(dir: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map(|t| t.is_dir()).unwrap_or(false))
+        .filter(|entry| entry.file_name().to_str().map(is_shard).unwrap_or(false))
+        .map(|entry| entry.path())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileStorage, Layout};
+    use crate::storage::{temp_location, Storage};
+    use std::fs;
+    use std::path::Path;
+
+    fn storage(layout: Layout) -> FileStorage {
+        FileStorage {
+            location: String::from("db"),
+            layout,
+        }
+    }
+
+    #[test]
+    fn reserved_names_cant_escape() {
+        for layout in [Layout::Flat, Layout::Sharded, Layout::Hierarchical] {
+            let storage = storage(layout);
+            assert!(storage.check("__CACHE__").is_ok());
+            assert!(storage.check("__INDEX__/../../escaped__").is_err());
+            assert!(storage.check("__..__\0").is_err());
+        }
+        assert!(storage(Layout::Hierarchical).check("a/__CACHE__").is_err());
+    }
+
+    #[test]
+    fn migrates_short_keys() {
+        let location = temp_location("migrate");
+        let mut flat = FileStorage::new(&location);
+        for i in 0..300 {
+            flat.put(&i.to_string(), i.to_string().as_bytes()).unwrap();
+        }
+        flat.namespace("users").unwrap().put("1", b"one").unwrap();
+
+        let sharded = FileStorage::new_sharded(&location);
+        let reopened = FileStorage::new(&location);
+        for storage in &[&sharded, &reopened] {
+            assert!(storage.layout == Layout::Sharded);
+            assert_eq!(storage.list().len(), 300);
+            for i in 0..300 {
+                assert_eq!(
+                    storage.get(&i.to_string()).unwrap(),
+                    i.to_string().as_bytes()
+                );
+            }
+            assert_eq!(
+                storage.namespace("users").unwrap().get("1").unwrap(),
+                b"one"
+            );
+        }
+        fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[test]
+    fn collections_named_like_shards_stop_migrations() {
+        let location = temp_location("migrate-shard-names");
+        let mut flat = FileStorage::new(&location);
+        flat.put("10", b"ten").unwrap();
+        flat.namespace("ab").unwrap().put("1", b"one").unwrap();
+
+        assert!(FileStorage::new(&location).migrate_to_sharded().is_err());
+        let reopened = FileStorage::new(&location);
+        assert!(reopened.layout == Layout::Flat);
+        assert_eq!(reopened.get("10").unwrap(), b"ten");
+        assert_eq!(reopened.namespace("ab").unwrap().get("1").unwrap(), b"one");
+        fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[test]
+    fn deletes_hierarchical_trees() {
+        let location = temp_location("hierarchical");
+        let mut storage = FileStorage::new_hierarchical(&location);
+        for key in &[
+            "tenants/acme/users/1",
+            "tenants/acme/users/2",
+            "tenants/globex",
+        ] {
+            storage.put(key, b"").unwrap();
+        }
+        assert!(storage.put("tenants/acme", b"").is_err());
+        assert_eq!(storage.list_children("tenants"), vec!["acme", "globex"]);
+
+        assert!(storage.delete_tree("/").is_err());
+        storage.delete_tree("tenants/acme/").unwrap();
+        assert_eq!(storage.list(), vec!["tenants/globex"]);
+        assert!(!Path::new(&location).join("tenants/acme").exists());
+        storage.delete_tree("tenants/globex").unwrap();
+        assert!(storage.list().is_empty());
+        assert!(!Path::new(&location).join("tenants").exists());
+        fs::remove_dir_all(&location).unwrap();
+    }
+}
@@ -0,0 +1,537 @@
+use super::{is_reserved, Storage};
+use crate::error::DBError;
+use hashbrown::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+const DATA_EXTENSION: &str = "data";
+const HINT_EXTENSION: &str = "hint";
+const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+// checksum, key length, value length
+const HEADER_SIZE: u64 = 12;
+// Value length of a record that marks its key as deleted
+const TOMBSTONE: u32 = u32::MAX;
+
+/// Appends every save to a log instead of writing one file per key, which keeps saves fast no
+/// matter how many keys there are. Where the latest value of every key is in the log is kept in
+/// memory, so loads are a single read.
+///
+/// The log is split into segments. Overwritten and deleted values stay in the segments until
+/// they're [compacted](#method.compact), which can also happen automatically with
+/// [`with_auto_compaction`](#method.with_auto_compaction). Every full segment gets a hint file
+/// listing where its keys are, so opening doesn't require reading every value.
+pub struct LogStorage {
+    location: String,
+    keydir: HashMap<String, Position>,
+    active: File,
+    active_id: u64,
+    active_size: u64,
+    // Everything written to the active segment, for its hint file
+    active_records: Vec<Record>,
+    max_segment_size: u64,
+    // Bytes taken up by records of keys that have since been overwritten or deleted
+    garbage: u64,
+    // Bytes taken up by the latest records of existing keys
+    live: u64,
+    auto_compaction: Option<u64>,
+    readers: Mutex<HashMap<u64, File>>,
+}
+
+#[derive(Clone, Copy)]
+struct Position {
+    segment: u64,
+    // Where the record starts
+    offset: u64,
+    key_len: u32,
+    value_len: u32,
+}
+
+impl Position {
+    fn record_size(&self) -> u64 {
+        HEADER_SIZE + u64::from(self.key_len) + u64::from(self.value_len)
+    }
+}
+
+// What a log or hint file says about a key
+struct Record {
+    key: String,
+    position: Position,
+    deleted: bool,
+}
+
+impl LogStorage {
+    /// Use the log in the directory at location, creating it if needed.
+    pub fn new(location: &str) -> Self {
+        Self::open(location)
+            .unwrap_or_else(|e| panic!("sfsdb: Could not open database at {}: {}", location, e))
+    }
+
+    fn open(location: &str) -> io::Result<Self> {
+        fs::create_dir_all(location)?;
+        let segments = segment_ids(Path::new(location))?;
+
+        let mut keydir: HashMap<String, Position> = HashMap::new();
+        let mut garbage = 0;
+        let mut active_size = 0;
+        let mut active_records = Vec::new();
+        for (i, id) in segments.iter().enumerate() {
+            let last = i == segments.len() - 1;
+            let hint = segment_path(location, *id, HINT_EXTENSION);
+            // The last segment is still being appended to, so it never has a hint
+            let (records, size) = match (last, read_hint(&hint)) {
+                (false, Ok(records)) => (records, 0),
+                _ => scan_segment(&segment_path(location, *id, DATA_EXTENSION), *id, last)?,
+            };
+            for record in &records {
+                if let Some(old) = keydir.remove(&record.key) {
+                    garbage += old.record_size();
+                }
+                if record.deleted {
+                    garbage += record.position.record_size();
+                } else {
+                    keydir.insert(record.key.clone(), record.position);
+                }
+            }
+            active_size = size;
+            active_records = records;
+        }
+
+        let live = keydir.values().map(Position::record_size).sum();
+        let active_id = segments.last().cloned().unwrap_or(0);
+        let active = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(location, active_id, DATA_EXTENSION))?;
+
+        Ok(LogStorage {
+            location: String::from(location),
+            keydir,
+            active,
+            active_id,
+            active_size,
+            active_records,
+            max_segment_size: DEFAULT_SEGMENT_SIZE,
+            garbage,
+            live,
+            auto_compaction: None,
+            readers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Start a new segment once the current one grows beyond this many bytes. Defaults to 64MiB.
+    pub fn with_max_segment_size(mut self, bytes: u64) -> Self {
+        self.max_segment_size = bytes.max(1);
+        self
+    }
+
+    /// Compact automatically whenever overwritten and deleted values take up more than
+    /// `min_garbage` bytes, and more space than the values still in use.
+    pub fn with_auto_compaction(mut self, min_garbage: u64) -> Self {
+        self.auto_compaction = Some(min_garbage);
+        self
+    }
+
+    /// Bytes taken up by values that have since been overwritten or deleted.
+    pub fn garbage(&self) -> u64 {
+        self.garbage
+    }
+
+    /// Rewrite all values still in use into new segments and remove the old ones, reclaiming the
+    /// space of everything that's been overwritten or deleted.
+    pub fn compact(&mut self) -> Result<(), DBError> {
+        self.try_compact()
+            .map_err(|e| DBError::save(&format!("Could not compact {} ({})", self.location, e)))
+    }
+
+    fn try_compact(&mut self) -> io::Result<()> {
+        let old = segment_ids(Path::new(&self.location))?;
+
+        // Everything goes into segments after the current ones, so if this is interrupted the
+        // log still replays to the same state
+        self.rotate()?;
+        let mut keys: Vec<(String, Position)> =
+            self.keydir.iter().map(|(k, p)| (k.clone(), *p)).collect();
+        // Reading in the order things were written keeps it mostly sequential
+        keys.sort_by_key(|(_, p)| (p.segment, p.offset));
+        for (key, position) in keys {
+            let value = self.read_value(&position)?;
+            self.append(&key, Some(&value))?;
+        }
+        self.rotate()?;
+
+        self.readers().clear();
+        for id in old {
+            fs::remove_file(segment_path(&self.location, id, DATA_EXTENSION))?;
+            fs::remove_file(segment_path(&self.location, id, HINT_EXTENSION)).ok();
+        }
+        self.garbage = 0;
+        Ok(())
+    }
+
+    fn maybe_compact(&mut self) -> Result<(), DBError> {
+        match self.auto_compaction {
+            Some(min) if self.garbage > min && self.garbage > self.live => self.compact(),
+            _ => Ok(()),
+        }
+    }
+
+    // Seal the current segment with a hint file and continue in a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        // Compaction deletes the old segments right after, so the new ones have to be on disk
+        self.active.sync_data()?;
+        write_hint(
+            &segment_path(&self.location, self.active_id, HINT_EXTENSION),
+            &self.active_records,
+        )?;
+        self.active_id += 1;
+        self.active_size = 0;
+        self.active_records.clear();
+        self.active = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.location, self.active_id, DATA_EXTENSION))?;
+        Ok(())
+    }
+
+    // Write a record for key to the end of the log, None meaning it's deleted
+    fn append(&mut self, key: &str, value: Option<&[u8]>) -> io::Result<()> {
+        if self.active_size >= self.max_segment_size {
+            self.rotate()?;
+        }
+        let value_len = match value {
+            Some(v) if v.len() >= TOMBSTONE as usize => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "value too large",
+                ))
+            }
+            Some(v) => v.len() as u32,
+            None => TOMBSTONE,
+        };
+        let mut body = Vec::with_capacity(8 + key.len() + value.map(|v| v.len()).unwrap_or(0));
+        body.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        body.extend_from_slice(&value_len.to_le_bytes());
+        body.extend_from_slice(key.as_bytes());
+        if let Some(v) = value {
+            body.extend_from_slice(v);
+        }
+        let mut record = Vec::with_capacity(4 + body.len());
+        record.extend_from_slice(&checksum(&body).to_le_bytes());
+        record.extend_from_slice(&body);
+        self.active.write_all(&record)?;
+
+        let position = Position {
+            segment: self.active_id,
+            offset: self.active_size,
+            key_len: key.len() as u32,
+            value_len: value.map(|v| v.len() as u32).unwrap_or(0),
+        };
+        self.active_size += record.len() as u64;
+        if let Some(old) = self.keydir.remove(key) {
+            self.garbage += old.record_size();
+            self.live -= old.record_size();
+        }
+        match value {
+            Some(_) => {
+                self.live += position.record_size();
+                self.keydir.insert(key.to_owned(), position);
+            }
+            None => self.garbage += position.record_size(),
+        }
+        self.active_records.push(Record {
+            key: key.to_owned(),
+            position,
+            deleted: value.is_none(),
+        });
+        Ok(())
+    }
+
+    fn readers(&self) -> MutexGuard<'_, HashMap<u64, File>> {
+        self.readers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn read_value(&self, position: &Position) -> io::Result<Vec<u8>> {
+        let mut readers = self.readers();
+        if !readers.contains_key(&position.segment) {
+            let file = File::open(segment_path(
+                &self.location,
+                position.segment,
+                DATA_EXTENSION,
+            ))?;
+            readers.insert(position.segment, file);
+        }
+        let file = readers.get_mut(&position.segment).unwrap();
+        file.seek(SeekFrom::Start(
+            position.offset + HEADER_SIZE + u64::from(position.key_len),
+        ))?;
+        let mut value = vec![0; position.value_len as usize];
+        file.read_exact(&mut value)?;
+        Ok(value)
+    }
+}
+
+impl Storage for LogStorage {
+    fn location(&self) -> &str {
+        &self.location
+    }
+    fn is_removed(&self) -> bool {
+        !Path::new(&self.location).is_dir()
+    }
+    fn get(&self, key: &str) -> Result<Vec<u8>, DBError> {
+        match self.keydir.get(key) {
+            Some(position) => self
+                .read_value(position)
+                .map_err(|e| DBError::load(&format!("{}", e))),
+            None => Err(DBError::load(&format!("{} does not exist", key))),
+        }
+    }
+    fn get_many(&self, keys: &[&str]) -> Vec<Result<Vec<u8>, DBError>> {
+        let mut values: Vec<Result<Vec<u8>, DBError>> = keys
+            .iter()
+            .map(|key| Err(DBError::load(&format!("{} does not exist", key))))
+            .collect();
+        // Read in the order records are in the segments, rather than seeking back and forth
+        let mut found: Vec<(usize, &Position)> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| self.keydir.get(*key).map(|position| (i, position)))
+            .collect();
+        found.sort_unstable_by_key(|(_, position)| (position.segment, position.offset));
+        for (i, position) in found {
+            values[i] = self
+                .read_value(position)
+                .map_err(|e| DBError::load(&format!("{}", e)));
+        }
+        values
+    }
+    fn put(&mut self, key: &str, data: &[u8]) -> Result<(), DBError> {
+        self.append(key, Some(data))
+            .map_err(|e| DBError::save(&format!("Could not write {} ({})", key, e)))?;
+        self.maybe_compact()
+    }
+    fn put_durable(&mut self, key: &str, data: &[u8]) -> Result<(), DBError> {
+        // A torn record fails its checksum and is dropped on open, leaving the previous value
+        self.append(key, Some(data))
+            .and_then(|_| self.active.sync_data())
+            .map_err(|e| DBError::save(&format!("Could not write {} ({})", key, e)))?;
+        self.maybe_compact()
+    }
+    fn delete(&mut self, key: &str) -> Result<(), DBError> {
+        if !self.keydir.contains_key(key) {
+            return Err(DBError::delete(&format!("Could not delete {}", key)));
+        }
+        self.append(key, None)
+            .map_err(|e| DBError::delete(&format!("Could not delete {} ({})", key, e)))?;
+        self.maybe_compact()
+    }
+    fn contains(&self, key: &str) -> bool {
+        self.keydir.contains_key(key)
+    }
+    fn list(&self) -> Vec<String> {
+        self.keydir
+            .keys()
+            .filter(|k| !is_reserved(k))
+            .cloned()
+            .collect()
+    }
+    fn namespace(&self, name: &str) -> Result<Self, DBError> {
+        let mut path = PathBuf::from(&self.location);
+        path.push(name);
+        let location = path.to_string_lossy();
+        let mut log = LogStorage::open(&location).map_err(|e| {
+            DBError::load(&format!("Could not open database at {} ({})", location, e))
+        })?;
+        log.max_segment_size = self.max_segment_size;
+        log.auto_compaction = self.auto_compaction;
+        Ok(log)
+    }
+}
+
+fn segment_path(location: &str, id: u64, extension: &str) -> PathBuf {
+    let mut path = PathBuf::from(location);
+    path.push(format!("{:010}.{}", id, extension));
+    path
+}
+
+// Ids of all segments in the directory, oldest first
+fn segment_ids(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut ids: Vec<u64> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .map(|e| e == DATA_EXTENSION)
+                .unwrap_or(false)
+        })
+        .filter_map(|path| path.file_stem()?.to_str()?.parse().ok())
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+// FNV-1a, to notice records that were only partially written
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, b| {
+        (hash ^ u32::from(*b)).wrapping_mul(0x0100_0193)
+    })
+}
+
+// Read every record of a segment, returning them and the size of the valid part. A record that
+// was only partially written can only be at the end of the last segment, which is cut off there.
+fn scan_segment(path: &Path, id: u64, last: bool) -> io::Result<(Vec<Record>, u64)> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while let Some((record, size)) = parse_record(&bytes[offset..], id, offset as u64) {
+        records.push(record);
+        offset += size;
+    }
+    if offset < bytes.len() {
+        if !last {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is corrupted", path.display()),
+            ));
+        }
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(offset as u64)?;
+    }
+    Ok((records, offset as u64))
+}
+
+fn parse_record(bytes: &[u8], segment: u64, offset: u64) -> Option<(Record, usize)> {
+    if bytes.len() < HEADER_SIZE as usize {
+        return None;
+    }
+    let stored = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let key_len = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    let value_len = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
+    let deleted = value_len == TOMBSTONE;
+    let stored_value_len = if deleted { 0 } else { value_len };
+    let size = HEADER_SIZE as usize + key_len as usize + stored_value_len as usize;
+    if bytes.len() < size || checksum(&bytes[4..size]) != stored {
+        return None;
+    }
+    let key = String::from_utf8(bytes[12..12 + key_len as usize].to_vec()).ok()?;
+    Some((
+        Record {
+            key,
+            position: Position {
+                segment,
+                offset,
+                key_len,
+                value_len: stored_value_len,
+            },
+            deleted,
+        },
+        size,
+    ))
+}
+
+// A hint file lists the records of a segment without their values: per record the length of
+// the key, the key, the offset of the record and the length of its value (or TOMBSTONE)
+fn write_hint(path: &Path, records: &[Record]) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    for record in records {
+        let value_len = if record.deleted {
+            TOMBSTONE
+        } else {
+            record.position.value_len
+        };
+        bytes.extend_from_slice(&(record.key.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(record.key.as_bytes());
+        bytes.extend_from_slice(&record.position.offset.to_le_bytes());
+        bytes.extend_from_slice(&value_len.to_le_bytes());
+    }
+    let mut file = File::create(path)?;
+    file.write_all(&checksum(&bytes).to_le_bytes())?;
+    file.write_all(&bytes)
+}
+
+fn read_hint(path: &Path) -> io::Result<Vec<Record>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid hint file");
+    let bytes = fs::read(path)?;
+    if bytes.len() < 4
+        || checksum(&bytes[4..]) != u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    {
+        return Err(invalid());
+    }
+    let segment = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(invalid)?;
+    let mut records = Vec::new();
+    let mut rest = &bytes[4..];
+    while !rest.is_empty() {
+        let mut key_len = [0; 4];
+        rest.read_exact(&mut key_len)?;
+        let key_len = u32::from_le_bytes(key_len);
+        let mut key = vec![0; key_len as usize];
+        rest.read_exact(&mut key)?;
+        let mut offset = [0; 8];
+        rest.read_exact(&mut offset)?;
+        let mut value_len = [0; 4];
+        rest.read_exact(&mut value_len)?;
+        let value_len = u32::from_le_bytes(value_len);
+        let deleted = value_len == TOMBSTONE;
+        records.push(Record {
+            key: String::from_utf8(key).map_err(|_| invalid())?,
+            position: Position {
+                segment,
+                offset: u64::from_le_bytes(offset),
+                key_len,
+                value_len: if deleted { 0 } else { value_len },
+            },
+            deleted,
+        });
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LogStorage;
+    use crate::storage::{temp_location, Storage};
+    use std::fs;
+
+    fn check(storage: &LogStorage) {
+        let mut keys = storage.list();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "c"]);
+        assert_eq!(storage.get("a").unwrap(), b"a2");
+        assert_eq!(storage.get("c").unwrap(), b"c1");
+        assert!(storage.get("b").is_err());
+    }
+
+    #[test]
+    fn compacts_and_reopens() {
+        let location = temp_location("log");
+        let mut storage = LogStorage::new(&location).with_max_segment_size(32);
+        storage.put("a", b"a1").unwrap();
+        storage.put("b", b"b1").unwrap();
+        storage.put("c", b"c1").unwrap();
+        storage.put("a", b"a2").unwrap();
+        storage.delete("b").unwrap();
+        assert!(storage.garbage() > 0);
+        drop(storage);
+
+        // From the hint files of full segments and the records of the last one
+        let mut storage = LogStorage::new(&location);
+        check(&storage);
+        storage.compact().unwrap();
+        assert_eq!(storage.garbage(), 0);
+        check(&storage);
+        drop(storage);
+        check(&LogStorage::new(&location));
+        fs::remove_dir_all(&location).unwrap();
+    }
+}
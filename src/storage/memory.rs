@@ -0,0 +1,123 @@
+use super::{is_reserved, Storage};
+use crate::error::DBError;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// Separates the namespace from the key, can't be part of a file name so it can't be in a key either
+const SEPARATOR: char = '\0';
+
+/// Keeps everything in memory, nothing is ever written to disk. Mostly useful for tests.
+///
+/// Clones share the same data, so a database can be "reopened" by giving it a clone of the
+/// storage a previous one used.
+#[derive(Clone)]
+pub struct MemoryStorage {
+    location: String,
+    prefix: String,
+    data: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage {
+            location: String::from(":memory:"),
+            prefix: String::new(),
+            data: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, Vec<u8>>> {
+        self.data.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn full_key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn location(&self) -> &str {
+        &self.location
+    }
+    fn get(&self, key: &str) -> Result<Vec<u8>, DBError> {
+        self.lock()
+            .get(&self.full_key(key))
+            .cloned()
+            .ok_or_else(|| DBError::load(&format!("{} does not exist", key)))
+    }
+    fn get_many(&self, keys: &[&str]) -> Vec<Result<Vec<u8>, DBError>> {
+        let data = self.lock();
+        keys.iter()
+            .map(|key| {
+                data.get(&self.full_key(key))
+                    .cloned()
+                    .ok_or_else(|| DBError::load(&format!("{} does not exist", key)))
+            })
+            .collect()
+    }
+    fn append(&mut self, key: &str, data: &[u8]) -> Result<(), DBError> {
+        self.lock()
+            .entry(self.full_key(key))
+            .or_default()
+            .extend_from_slice(data);
+        Ok(())
+    }
+    fn put(&mut self, key: &str, data: &[u8]) -> Result<(), DBError> {
+        self.lock().insert(self.full_key(key), data.to_vec());
+        Ok(())
+    }
+    fn delete(&mut self, key: &str) -> Result<(), DBError> {
+        match self.lock().remove(&self.full_key(key)) {
+            Some(_) => Ok(()),
+            None => Err(DBError::delete(&format!("Could not delete {}", key))),
+        }
+    }
+    fn contains(&self, key: &str) -> bool {
+        self.lock().contains_key(&self.full_key(key))
+    }
+    fn list(&self) -> Vec<String> {
+        self.lock()
+            .range(self.prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&self.prefix))
+            .map(|(k, _)| &k[self.prefix.len()..])
+            .filter(|k| !k.contains(SEPARATOR) && !is_reserved(k))
+            .map(String::from)
+            .collect()
+    }
+    fn namespace(&self, name: &str) -> Result<Self, DBError> {
+        Ok(MemoryStorage {
+            location: format!("{}/{}", self.location, name),
+            prefix: format!("{}{}{}", self.prefix, name, SEPARATOR),
+            data: self.data.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryStorage;
+    use crate::storage::Storage;
+
+    #[test]
+    fn deletes_trees() {
+        let mut storage = MemoryStorage::new();
+        for key in &["tenants/acme/users/1", "tenants/acme2", "tenants/globex"] {
+            storage.put(key, b"").unwrap();
+        }
+        let mut users = storage.namespace("users").unwrap();
+        users.put("tenants/acme/1", b"").unwrap();
+
+        assert_eq!(storage.list_children(""), vec!["tenants"]);
+        storage.delete_tree("tenants/acme").unwrap();
+        let mut keys = storage.list();
+        keys.sort();
+        assert_eq!(keys, vec!["tenants/acme2", "tenants/globex"]);
+        assert_eq!(users.list(), vec!["tenants/acme/1"]);
+    }
+}
@@ -0,0 +1,161 @@
+mod file;
+mod log;
+mod memory;
+
+pub use self::log::LogStorage;
+pub use file::FileStorage;
+pub use memory::MemoryStorage;
+
+use crate::error::DBError;
+use rmp_serde::{decode, encode};
+use serde::{Deserialize, Serialize};
+
+/// Where a database keeps its data. Databases only ever hand already encoded bytes to their
+/// storage, so a storage backend doesn't need to know anything about the values.
+///
+/// Keys wrapped in double underscores (such as `__CACHE__`) are used by sfsdb itself. They're
+/// stored like any other key, but never listed.
+pub trait Storage {
+    /// A description of where the data is, such as the path of a directory.
+    fn location(&self) -> &str;
+    /// Whether the storage was removed from under the database, such as by deleting its
+    /// directory. Nothing is written when the database is dropped once it is.
+    fn is_removed(&self) -> bool {
+        false
+    }
+    /// Get the bytes saved under key.
+    fn get(&self, key: &str) -> Result<Vec<u8>, DBError>;
+    /// Get the bytes of several keys at once, in the same order as keys. Backends that can read
+    /// several keys faster than one by one, such as by reading them in the order they're on disk,
+    /// override this.
+    fn get_many(&self, keys: &[&str]) -> Vec<Result<Vec<u8>, DBError>> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+    /// Save bytes under key, replacing whatever was there before.
+    fn put(&mut self, key: &str, data: &[u8]) -> Result<(), DBError>;
+    /// Like put, but the bytes are on disk once it returns, and a crash halfway leaves either the
+    /// old or the new bytes. Used for things that must never go backwards, such as sequences.
+    fn put_durable(&mut self, key: &str, data: &[u8]) -> Result<(), DBError> {
+        self.put(key, data)
+    }
+    /// Add bytes to the end of what's saved under key, which is created if it doesn't exist. Used
+    /// for logs, such as the one of a compact index. Backends that can append without rewriting
+    /// everything override this.
+    fn append(&mut self, key: &str, data: &[u8]) -> Result<(), DBError> {
+        let mut bytes = if self.contains(key) {
+            self.get(key)?
+        } else {
+            Vec::new()
+        };
+        bytes.extend_from_slice(data);
+        self.put(key, &bytes)
+    }
+    /// Remove key and its bytes.
+    fn delete(&mut self, key: &str) -> Result<(), DBError>;
+    /// Check if key exists.
+    fn contains(&self, key: &str) -> bool;
+    /// All keys, except the ones used by sfsdb itself and those in namespaces.
+    fn list(&self) -> Vec<String>;
+    /// The names directly below prefix when keys are read as `/` separated paths, sorted. Both
+    /// keys and parts of longer keys count, so the children of `tenants` in a storage with
+    /// `tenants/acme/users/42` and `tenants/globex` are `acme` and `globex`. An empty prefix
+    /// lists the top level.
+    fn list_children(&self, prefix: &str) -> Vec<String> {
+        children(&self.list(), prefix)
+    }
+    /// Remove prefix and every key below it, such as `tenants/acme` and `tenants/acme/users/42`
+    /// for the prefix `tenants/acme`.
+    fn delete_tree(&mut self, prefix: &str) -> Result<(), DBError>
+    where
+        Self: Sized,
+    {
+        delete_listed_tree(self, prefix)
+    }
+    /// A separate storage of the same kind, with keys that never collide with this one. Used for
+    /// things such as the index of an indexed database. Fails for names the storage can't tell
+    /// apart from its own layout.
+    fn namespace(&self, name: &str) -> Result<Self, DBError>
+    where
+        Self: Sized;
+}
+
+// An empty directory for a test to keep a database in
+#[cfg(test)]
+pub(crate) fn temp_location(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("sfsdb-{}-{}", name, std::process::id()));
+    std::fs::remove_dir_all(&path).ok();
+    path.to_string_lossy().into_owned()
+}
+
+// Names wrapped in double underscores are used by sfsdb itself, such as __INDEX__
+pub(crate) fn is_reserved(name: &str) -> bool {
+    name.len() > 4 && name.starts_with("__") && name.ends_with("__")
+}
+
+// The prefix of a tree to delete, refusing to delete everything by accident
+pub(crate) fn tree_root(prefix: &str) -> Result<&str, DBError> {
+    let root = prefix.trim_end_matches('/');
+    if root.is_empty() {
+        return Err(DBError::delete(
+            "Refusing to delete the tree of an empty prefix",
+        ));
+    }
+    Ok(root)
+}
+
+// Whether key is the root of the tree or somewhere below it
+pub(crate) fn in_tree(key: &str, root: &str) -> bool {
+    key.starts_with(root) && (key.len() == root.len() || key[root.len()..].starts_with('/'))
+}
+
+// The names directly below prefix among keys
+pub(crate) fn children(keys: &[String], prefix: &str) -> Vec<String> {
+    let prefix = prefix.trim_end_matches('/');
+    let mut children: Vec<String> = keys
+        .iter()
+        .filter_map(|key| child_of(key, prefix))
+        .map(String::from)
+        .collect();
+    children.sort();
+    children.dedup();
+    children
+}
+
+// Delete a tree one listed key at a time, for storage that has no faster way
+pub(crate) fn delete_listed_tree<S: Storage>(storage: &mut S, prefix: &str) -> Result<(), DBError> {
+    let root = tree_root(prefix)?;
+    for key in storage.list().iter().filter(|k| in_tree(k, root)) {
+        storage.delete(key)?;
+    }
+    Ok(())
+}
+
+// The first part of key below prefix, if key is below it at all
+fn child_of<'a>(key: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = if prefix.is_empty() {
+        key
+    } else {
+        key.strip_prefix(prefix)?.strip_prefix('/')?
+    };
+    rest.split('/').next().filter(|child| !child.is_empty())
+}
+
+pub(crate) fn load<T, S: Storage>(storage: &S, key: &str) -> Result<T, DBError>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    let bytes = storage.get(key)?;
+    decode::from_slice(&bytes)
+        .map_err(|e| DBError::load(&format!("Unable to decode {} ({})", key, e)))
+}
+
+pub(crate) fn save<T: Serialize, S: Storage>(
+    storage: &mut S,
+    key: &str,
+    value: &T,
+) -> Result<(), DBError> {
+    match encode::to_vec(value) {
+        Ok(v) => storage.put(key, &v),
+        Err(_) => Err(DBError::save(&format!("Unable to serialize {}", key))),
+    }
+}